@@ -0,0 +1,357 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use super::GameFrame;
+use crate::types::{Counter, Entity, Group, Item, Point, ECS};
+
+// Everything that differs between two frames. Entities from before the frames' branches split are
+// matched up by key. Sibling branches reuse keys for whatever they add after that, as they do ids
+// for portals and items, so those are matched up by where they are instead.
+#[derive(Clone, Debug, Default)]
+pub struct FrameDiff {
+    // Entity, old position, new position.
+    pub moved: Vec<(Entity, Point, Point)>,
+    // Entities alive in only one of the frames, with their positions if they have one.
+    pub appeared: Vec<(Entity, Option<Point>)>,
+    pub removed: Vec<(Entity, Option<Point>)>,
+    // Player, item, and how many more of it they hold.
+    pub inventory_changes: Vec<(Entity, Item, i64)>,
+    pub portals_opened: Vec<Point>,
+    pub portals_closed: Vec<Point>,
+    pub items_added: Vec<(Item, Point)>,
+    pub items_taken: Vec<(Item, Point)>,
+    // Entity, counter, old value, new value.
+    pub counter_changes: Vec<(Entity, Counter, i64, i64)>,
+    // Entity, group, and whether the group is now enabled.
+    pub group_changes: Vec<(Entity, Group, bool)>,
+}
+
+impl FrameDiff {
+    // The diff between two frames on the same branch, such as a frame and its child.
+    pub fn new(before: &GameFrame, after: &GameFrame) -> Self {
+        FrameDiff::across_branches(before, after, u32::max_value())
+    }
+    // The diff between frames whose branches split at a frame with `shared` entities.
+    pub fn across_branches(before: &GameFrame, after: &GameFrame, shared: u32) -> Self {
+        let mut diff = FrameDiff::default();
+        let (before_ecs, after_ecs) = (&before.ecs, &after.ecs);
+
+        for (entity, _) in after_ecs.entities.iter() {
+            if entity.index() < shared && !before_ecs.entities.contains_key(entity) {
+                let position = after_ecs.positions.get(entity).copied();
+                diff.appeared.push((entity, position));
+            }
+        }
+        for (entity, _) in before_ecs.entities.iter() {
+            if entity.index() >= shared {
+                continue;
+            }
+            if after_ecs.entities.contains_key(entity) {
+                diff.compare(before_ecs, entity, after_ecs, entity);
+            } else {
+                let position = before_ecs.positions.get(entity).copied();
+                diff.removed.push((entity, position));
+            }
+        }
+        // Entities added since the split are the same if they're the same kind of thing in the
+        // same place.
+        let added_since_split = |ecs: &ECS| -> Vec<(Entity, Option<Point>, bool)> {
+            ecs.entities
+                .iter()
+                .map(|(entity, _)| entity)
+                .filter(|entity| entity.index() >= shared)
+                .map(|entity| {
+                    let position = ecs.positions.get(entity).copied();
+                    (entity, position, ecs.players.contains_key(entity))
+                })
+                .collect()
+        };
+        let mut unmatched = added_since_split(before_ecs);
+        for (entity, position, is_player) in added_since_split(after_ecs) {
+            let matched = unmatched.iter().position(|&(_, old_position, was_player)| {
+                (old_position, was_player) == (position, is_player)
+            });
+            match matched {
+                Some(ix) => {
+                    let (old_entity, _, _) = unmatched.remove(ix);
+                    diff.compare(before_ecs, old_entity, after_ecs, entity);
+                }
+                None => diff.appeared.push((entity, position)),
+            }
+        }
+        for (entity, position, _) in unmatched {
+            diff.removed.push((entity, position));
+        }
+
+        let portals = |frame: &GameFrame| -> Vec<Point> {
+            frame
+                .portals
+                .iter()
+                .map(|(_, portal)| portal.player_position)
+                .collect()
+        };
+        let (before_portals, after_portals) = (portals(before), portals(after));
+        diff.portals_opened = difference(&after_portals, &before_portals);
+        diff.portals_closed = difference(&before_portals, &after_portals);
+        let items = |frame: &GameFrame| -> Vec<(Item, Point)> {
+            frame
+                .items
+                .iter()
+                .map(|(_, item_drop)| (item_drop.item.clone(), item_drop.position))
+                .collect()
+        };
+        let (before_items, after_items) = (items(before), items(after));
+        diff.items_added = difference(&after_items, &before_items);
+        diff.items_taken = difference(&before_items, &after_items);
+        diff
+    }
+    // Records how `old` in `before_ecs` changed into `new` in `after_ecs`, under `new`'s key.
+    fn compare(&mut self, before_ecs: &ECS, old: Entity, after_ecs: &ECS, new: Entity) {
+        if let (Some(&old_position), Some(&new_position)) =
+            (before_ecs.positions.get(old), after_ecs.positions.get(new))
+        {
+            if old_position != new_position {
+                self.moved.push((new, old_position, new_position));
+            }
+        }
+        if let (Some(old_inventory), Some(new_inventory)) =
+            (before_ecs.players.get(old), after_ecs.players.get(new))
+        {
+            let (old_counts, new_counts) =
+                (old_inventory.count_items(), new_inventory.count_items());
+            let items: HashSet<&Item> = old_counts.keys().chain(new_counts.keys()).collect();
+            for item in items {
+                let old_count = old_counts.get(item).map_or(0, |&c| c as i64);
+                let new_count = new_counts.get(item).map_or(0, |&c| c as i64);
+                if old_count != new_count {
+                    self.inventory_changes
+                        .push((new, item.clone(), new_count - old_count));
+                }
+            }
+        }
+        let old_counters = before_ecs.counters.get(old);
+        let new_counters = after_ecs.counters.get(new);
+        let counters: HashSet<Counter> = old_counters
+            .into_iter()
+            .chain(new_counters)
+            .flat_map(|counters| counters.keys().copied())
+            .collect();
+        for counter in counters {
+            let value = |counters: Option<&HashMap<Counter, i64>>| {
+                counters
+                    .and_then(|counters| counters.get(&counter))
+                    .copied()
+                    .unwrap_or(0)
+            };
+            let (old_value, new_value) = (value(old_counters), value(new_counters));
+            if old_value != new_value {
+                self.counter_changes
+                    .push((new, counter, old_value, new_value));
+            }
+        }
+        let old_disabled = before_ecs
+            .disabled_event_groups
+            .get(old)
+            .copied()
+            .unwrap_or_default();
+        let new_disabled = after_ecs
+            .disabled_event_groups
+            .get(new)
+            .copied()
+            .unwrap_or_default();
+        for group in (old_disabled ^ new_disabled).iter() {
+            self.group_changes
+                .push((new, group, !new_disabled.contains(group)));
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.moved.is_empty()
+            && self.appeared.is_empty()
+            && self.removed.is_empty()
+            && self.inventory_changes.is_empty()
+            && self.portals_opened.is_empty()
+            && self.portals_closed.is_empty()
+            && self.items_added.is_empty()
+            && self.items_taken.is_empty()
+            && self.counter_changes.is_empty()
+            && self.group_changes.is_empty()
+    }
+    // One line for each change that doesn't show up on the map.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (entity, item, change) in self.inventory_changes.iter() {
+            lines.push(format!("{:?}: {:+} {:?}", entity, change, item));
+        }
+        for (entity, counter, old_value, new_value) in self.counter_changes.iter() {
+            lines.push(format!(
+                "{:?}: {} {} -> {}",
+                entity,
+                counter.name(),
+                old_value,
+                new_value
+            ));
+        }
+        for (entity, group, enabled) in self.group_changes.iter() {
+            let state = if *enabled { "enabled" } else { "disabled" };
+            lines.push(format!("{:?}: {:?} {}", entity, group, state));
+        }
+        lines
+    }
+}
+
+// What's in `xs` but not in `ys`, counting duplicates separately.
+fn difference<T: Clone + Eq + Hash>(xs: &[T], ys: &[T]) -> Vec<T> {
+    let mut unmatched: HashMap<&T, usize> = HashMap::new();
+    for y in ys {
+        *unmatched.entry(y).or_insert(0) += 1;
+    }
+    xs.iter()
+        .filter(|x| match unmatched.get_mut(x) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                false
+            }
+            _ => true,
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::FrameDiff;
+    use crate::{
+        game_frame::GameFrame,
+        types::{ImageMap, Item, ItemDrop, Key, Point, Portal},
+    };
+    use ggez::nalgebra::Point2;
+
+    fn drop_key(frame: &mut GameFrame, position: Point) {
+        let item_drop = ItemDrop::new(&mut frame.ids, Item::Key(Key {}), position);
+        frame
+            .insert_item_drop(item_drop, 1)
+            .expect("Error inserting item");
+    }
+
+    #[test]
+    fn test_frame_diff() {
+        let image_map = ImageMap::mock();
+        let mut game_frame_0 = GameFrame::new();
+        let player_id = game_frame_0
+            .insert_player(&image_map, Point2::new(0, 0))
+            .expect("Error insterting player");
+        drop_key(&mut game_frame_0, Point2::new(1, 0));
+        let mut game_frame_1 = game_frame_0.clone();
+        *game_frame_1
+            .ecs
+            .positions
+            .get_mut(player_id)
+            .expect("Player without position") = Point2::new(1, 0);
+        game_frame_1
+            .items
+            .remove_by_position(&Point2::new(1, 0), None)
+            .expect("Couldn't take the item");
+        game_frame_1
+            .ecs
+            .players
+            .get_mut(player_id)
+            .expect("Player without inventory")
+            .insert(&Item::Key(Key {}))
+            .expect("Couldn't hold the item");
+        let diff = FrameDiff::new(&game_frame_0, &game_frame_1);
+        assert_eq!(
+            diff.moved,
+            vec![(player_id, Point2::new(0, 0), Point2::new(1, 0))]
+        );
+        assert_eq!(
+            diff.inventory_changes,
+            vec![(player_id, Item::Key(Key {}), 1)]
+        );
+        assert_eq!(
+            diff.items_taken,
+            vec![(Item::Key(Key {}), Point2::new(1, 0))]
+        );
+        assert!(diff.items_added.is_empty());
+        assert!(FrameDiff::new(&game_frame_1, &game_frame_1).is_empty());
+    }
+
+    // Sibling branches count ids from the same point, so different things end up sharing an id.
+    #[test]
+    fn test_frame_diff_across_branches() {
+        let mut game_frame_0 = GameFrame::new();
+        drop_key(&mut game_frame_0, Point2::new(0, 0));
+        let mut game_frame_1 = game_frame_0.clone();
+        let mut game_frame_2 = game_frame_0.clone();
+        drop_key(&mut game_frame_1, Point2::new(1, 0));
+        drop_key(&mut game_frame_2, Point2::new(2, 0));
+        let portal = Portal::new(&mut game_frame_1.ids, 0, Point2::new(3, 0));
+        game_frame_1
+            .portals
+            .insert(portal)
+            .expect("Error inserting portal");
+        let portal = Portal::new(&mut game_frame_2.ids, 0, Point2::new(4, 0));
+        game_frame_2
+            .portals
+            .insert(portal)
+            .expect("Error inserting portal");
+        let diff = FrameDiff::new(&game_frame_1, &game_frame_2);
+        assert_eq!(
+            diff.items_taken,
+            vec![(Item::Key(Key {}), Point2::new(1, 0))]
+        );
+        assert_eq!(
+            diff.items_added,
+            vec![(Item::Key(Key {}), Point2::new(2, 0))]
+        );
+        assert_eq!(diff.portals_closed, vec![Point2::new(3, 0)]);
+        assert_eq!(diff.portals_opened, vec![Point2::new(4, 0)]);
+        // The same item in the same place is no change, whatever its id.
+        let mut game_frame_3 = GameFrame::new();
+        game_frame_3.ids.fresh::<()>();
+        drop_key(&mut game_frame_3, Point2::new(0, 0));
+        assert!(FrameDiff::new(&game_frame_0, &game_frame_3).is_empty());
+    }
+
+    // Players added on each branch get the same key, so they're matched up by where they are.
+    #[test]
+    fn test_frame_diff_matches_new_entities_by_position() {
+        let image_map = ImageMap::mock();
+        let mut game_frame_0 = GameFrame::new();
+        let player_0 = game_frame_0
+            .insert_player(&image_map, Point2::new(0, 0))
+            .expect("Error inserting player");
+        let shared = game_frame_0.ecs.entities.inserted();
+        let mut game_frame_1 = game_frame_0.clone();
+        let mut game_frame_2 = game_frame_0.clone();
+        let player_1 = game_frame_1
+            .insert_player(&image_map, Point2::new(1, 0))
+            .expect("Error inserting player");
+        let player_2 = game_frame_2
+            .insert_player(&image_map, Point2::new(2, 0))
+            .expect("Error inserting player");
+        assert_eq!(player_1, player_2);
+        *game_frame_2
+            .ecs
+            .positions
+            .get_mut(player_0)
+            .expect("Player without position") = Point2::new(0, 1);
+        let diff = FrameDiff::across_branches(&game_frame_1, &game_frame_2, shared);
+        assert_eq!(
+            diff.moved,
+            vec![(player_0, Point2::new(0, 0), Point2::new(0, 1))]
+        );
+        assert_eq!(diff.removed, vec![(player_1, Some(Point2::new(1, 0)))]);
+        assert_eq!(diff.appeared, vec![(player_2, Some(Point2::new(2, 0)))]);
+        // Matching by key would take them for one player that moved.
+        let by_key = FrameDiff::new(&game_frame_1, &game_frame_2);
+        assert_eq!(by_key.moved.len(), 2);
+        // A player added in the same place on both branches is no change.
+        let mut game_frame_3 = game_frame_0.clone();
+        game_frame_3
+            .insert_player(&image_map, Point2::new(1, 0))
+            .expect("Error inserting player");
+        assert!(FrameDiff::across_branches(&game_frame_1, &game_frame_3, shared).is_empty());
+    }
+}
@@ -3,28 +3,52 @@ use crate::{
         self, ItemPortalGraph, ItemPortalGraphNode, PlayerPortalGraph, PlayerPortalGraphNode,
     },
     types::{
-        ActualInventory, DoubleMap, Entity, GameError, ImageMap, Inventory, Item, ItemDrop, Point,
-        Portal, ECS,
+        Action, ActualInventory, DoubleMap, Entity, GameError, IdGen, ImageMap, Inventory, Item,
+        ItemDrop, Point, Portal, Shared, StackMap, ECS,
     },
 };
-use petgraph::graphmap::GraphMap;
+use petgraph::graphmap::{DiGraphMap, GraphMap, NodeTrait};
 use std::{collections::HashMap, fmt};
 
+pub mod diff;
+
 #[derive(Clone)]
 pub struct GameFrame {
     pub portals: DoubleMap<Portal>,
-    pub items: DoubleMap<ItemDrop>,
-    pub player_portal_graph: PlayerPortalGraph,
-    pub item_portal_graphs: HashMap<Item, ItemPortalGraph>,
+    pub items: StackMap<ItemDrop>,
+    pub player_portal_graph: Shared<PlayerPortalGraph>,
+    pub item_portal_graphs: HashMap<Item, Shared<ItemPortalGraph>>,
     pub ecs: ECS,
+    pub ids: IdGen,
+    pub deaths: Vec<Death>,
+    pub scheduled: Vec<ScheduledAction>,
 }
 impl fmt::Debug for GameFrame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "GameFrame{{ portals:{:?}, items:{:?}, portal_graph:???}}",
-            self.portals, self.items
-        )
+        let item_portal_graphs: Vec<(&Item, Edges<_, _>)> = self
+            .item_portal_graphs
+            .iter()
+            .map(|(item, graph)| (item, Edges(&**graph)))
+            .collect();
+        f.debug_struct("GameFrame")
+            .field("portals", &self.portals)
+            .field("items", &self.items)
+            .field("player_portal_graph", &Edges(&*self.player_portal_graph))
+            .field("item_portal_graphs", &item_portal_graphs)
+            .field("ecs", &self.ecs)
+            .field("ids", &self.ids)
+            .field("deaths", &self.deaths)
+            .field("scheduled", &self.scheduled)
+            .finish()
+    }
+}
+
+// Lists a graph's edges with their weights, which `GraphMap`'s own `Debug` leaves out.
+struct Edges<'a, N: NodeTrait, E>(&'a DiGraphMap<N, E>);
+
+impl<'a, N: NodeTrait + fmt::Debug, E: fmt::Debug> fmt::Debug for Edges<'a, N, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.0.all_edges()).finish()
     }
 }
 
@@ -38,10 +62,13 @@ impl GameFrame {
     pub fn new() -> Self {
         GameFrame {
             portals: DoubleMap::new(),
-            items: DoubleMap::new(),
-            player_portal_graph: GraphMap::new(),
+            items: StackMap::new(),
+            player_portal_graph: Shared::new(GraphMap::new()),
             item_portal_graphs: HashMap::new(),
             ecs: ECS::default(),
+            ids: IdGen::new(),
+            deaths: Vec::new(),
+            scheduled: Vec::new(),
         }
     }
     pub fn insert_item_drop(
@@ -52,7 +79,7 @@ impl GameFrame {
         let item_portal_graph = self
             .item_portal_graphs
             .entry(drop.item.clone())
-            .or_insert_with(GraphMap::new);
+            .or_default();
         item_portal_graph.add_edge(
             ItemPortalGraphNode::Beginning,
             ItemPortalGraphNode::Dropped(drop.id),
@@ -75,6 +102,54 @@ impl GameFrame {
         );
         Ok(player)
     }
+    // Removes the player from the ECS, and ends its worldline at a `Death` node instead of `End`.
+    pub fn kill_player(&mut self, player: Entity, cause: &'static str) {
+        let position = *self
+            .ecs
+            .positions
+            .get(player)
+            .expect("Killed player without position");
+        let hypothetical = match self.ecs.players.get(player) {
+            Some(Inventory::Actual(_)) => false,
+            Some(Inventory::Hypothetical(_)) => true,
+            None => panic!("Tried to kill something that isn't a player"),
+        };
+        let (player_origin, _, _) = self
+            .player_portal_graph
+            .all_edges()
+            .find(|(_, _, &edge)| edge == player)
+            .expect("Couldn't find player in portal graph");
+        self.player_portal_graph
+            .remove_edge(player_origin, PlayerPortalGraphNode::End)
+            .expect("Tried to kill player unconnected to End");
+        self.player_portal_graph.add_edge(
+            player_origin,
+            PlayerPortalGraphNode::Death(player),
+            player,
+        );
+        self.ecs.entities.remove(player);
+        self.deaths.push(Death {
+            player,
+            position,
+            cause,
+            hypothetical,
+        });
+    }
+    // A dead actual player fails the branch outright. A dead hypothetical player can never close
+    // its portal, so the frame is doomed. Either way, there's no point in going further.
+    pub fn failure(&self) -> Option<GameError> {
+        self.deaths.first().map(|death| {
+            if death.hypothetical {
+                format!(
+                    "Doomed: a player from a portal {}, so its portal can never close",
+                    death.cause
+                )
+                .into()
+            } else {
+                format!("Branch failed: player {}", death.cause).into()
+            }
+        })
+    }
     pub fn wish(
         &mut self,
         player: Entity,
@@ -126,6 +201,22 @@ impl GameFrame {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Death {
+    pub player: Entity,
+    pub position: Point,
+    pub cause: &'static str,
+    pub hypothetical: bool,
+}
+
+// An action waiting to run `turns` turns from now, on behalf of `entity`.
+#[derive(Clone, Debug)]
+pub struct ScheduledAction {
+    pub turns: usize,
+    pub entity: Entity,
+    pub action: Action,
+}
+
 pub enum FrameWishResult {
     Success,
     NoItem,
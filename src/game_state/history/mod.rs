@@ -0,0 +1,172 @@
+use std::ops::Deref;
+
+use super::planning::apply_plan;
+use crate::{
+    game_frame::GameFrame,
+    tree::{Path, RoseTree},
+    types::{Entity, GameError, ImageMap, Inventory, InventoryCell, Item, Plan},
+};
+
+// A node in the history tree. Its frame can be evicted to save memory, since it can always be
+// recomputed by replaying the plans that led to it, then the node's wishes. The focus is always
+// kept materialised, and derefs to its frame.
+#[derive(Clone)]
+pub struct HistoryNode {
+    frame: Option<GameFrame>,
+    // When this node was last the focus, in `GameState::clock` ticks.
+    pub last_visited: u64,
+    // Made on this frame after its plan was applied, oldest first.
+    wishes: Vec<Wish>,
+}
+
+// A change to one slot of a hypothetical inventory, made on a frame that's already in the history.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Wish {
+    // One more of the item.
+    Add(Entity, usize, Item),
+    // One less of the item.
+    Remove(Entity, usize, Item),
+}
+
+impl Wish {
+    pub fn apply(&self, frame: &mut GameFrame) -> Result<(), GameError> {
+        match self {
+            Wish::Add(player, ix, item) => {
+                let clicked_item = match hypothetical_cell(frame, *player, *ix)? {
+                    None => Some(item.clone()),
+                    Some(cell) if cell.item == *item => None,
+                    Some(_) => Err("That slot holds a different item")?,
+                };
+                frame.wish(*player, *ix, clicked_item);
+            }
+            Wish::Remove(player, ix, item) => {
+                match hypothetical_cell(frame, *player, *ix)? {
+                    Some(cell) if cell.item == *item => {}
+                    _ => Err("That slot doesn't hold the item to unwish")?,
+                }
+                frame.unwish(*player, *ix)?;
+            }
+        }
+        Ok(())
+    }
+    // Whether making both wishes leaves the frame as it was.
+    fn cancels(&self, other: &Wish) -> bool {
+        match (self, other) {
+            (Wish::Add(p0, ix0, item0), Wish::Remove(p1, ix1, item1))
+            | (Wish::Remove(p0, ix0, item0), Wish::Add(p1, ix1, item1)) => {
+                p0 == p1 && ix0 == ix1 && item0 == item1
+            }
+            _ => false,
+        }
+    }
+}
+
+// The slot of a hypothetical inventory that a wish goes into.
+pub fn hypothetical_cell(
+    frame: &GameFrame,
+    player: Entity,
+    ix: usize,
+) -> Result<Option<&InventoryCell>, GameError> {
+    match frame.ecs.players.get(player) {
+        Some(Inventory::Hypothetical(hypothetical)) => {
+            Ok(hypothetical.cells.get(ix).ok_or("No such slot")?.as_ref())
+        }
+        Some(Inventory::Actual(_)) => Err("Can only wish into a hypothetical inventory".into()),
+        None => Err("No such player".into()),
+    }
+}
+
+impl HistoryNode {
+    pub fn new(frame: GameFrame) -> Self {
+        HistoryNode {
+            frame: Some(frame),
+            last_visited: 0,
+            wishes: Vec::new(),
+        }
+    }
+    pub fn is_evicted(&self) -> bool {
+        self.frame.is_none()
+    }
+    pub fn wishes(&self) -> &[Wish] {
+        &self.wishes
+    }
+    pub fn evict(&mut self) {
+        self.frame = None;
+    }
+    pub fn restore(&mut self, frame: GameFrame) {
+        self.frame = Some(frame);
+    }
+    // Makes the wish on the frame and records it. A wish that takes back the last one just drops it,
+    // so wishing and undoing leaves no trace.
+    pub fn wish(&mut self, wish: Wish) -> Result<(), GameError> {
+        let frame = self
+            .frame
+            .as_mut()
+            .expect("Wished on an evicted history frame without recomputing it");
+        wish.apply(frame)?;
+        match self.wishes.last() {
+            Some(last) if last.cancels(&wish) => {
+                self.wishes.pop();
+            }
+            _ => self.wishes.push(wish),
+        }
+        Ok(())
+    }
+    // This node's frame, as reached from `parent` with `plan`.
+    pub fn recompute(
+        &self,
+        image_map: &ImageMap,
+        parent: &GameFrame,
+        plan: &Plan,
+    ) -> Result<GameFrame, GameError> {
+        let mut frame = apply_plan(image_map, parent, plan)?;
+        for wish in &self.wishes {
+            wish.apply(&mut frame)?;
+        }
+        Ok(frame)
+    }
+}
+
+impl Deref for HistoryNode {
+    type Target = GameFrame;
+    fn deref(&self) -> &GameFrame {
+        self.frame
+            .as_ref()
+            .expect("Used an evicted history frame without recomputing it")
+    }
+}
+
+// Recomputes `tree` as if it had been reached from `parent` with `plan`, along with everything below
+// it. Stops at the first turn that fails, naming it by its path, which starts out as `tree`'s.
+pub fn replay_subtree(
+    image_map: &ImageMap,
+    parent: &GameFrame,
+    plan: &Plan,
+    tree: &RoseTree<HistoryNode, Plan>,
+    path: &mut Path,
+) -> Result<RoseTree<HistoryNode, Plan>, GameError> {
+    let frame = tree
+        .val
+        .recompute(image_map, parent, plan)
+        .map_err(|err| format!("The turn at {:?} no longer works: {}", path, err))?;
+    let mut children = Vec::with_capacity(tree.children.len());
+    for (i, (child_plan, child)) in tree.children.iter().enumerate() {
+        path.push(i);
+        let child = replay_subtree(image_map, &frame, child_plan, child, path)?;
+        path.pop();
+        children.push((child_plan.clone(), child));
+    }
+    // Frames that were evicted stay that way, so replaying a big subtree doesn't bring all of it
+    // into memory at once.
+    let frame = if tree.val.is_evicted() {
+        None
+    } else {
+        Some(frame)
+    };
+    let val = HistoryNode {
+        frame,
+        last_visited: tree.val.last_visited,
+        wishes: tree.val.wishes.clone(),
+    };
+    Ok(RoseTree { val, children })
+}
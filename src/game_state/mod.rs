@@ -2,11 +2,18 @@ use ggez::{
     event,
     graphics::{self, Color, DrawParam},
 };
-use std::f32::consts::PI;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    f32::consts::PI,
+    mem,
+};
 
 use ggez::nalgebra::{self, Similarity2, Vector2};
 
-use crate::{game_frame::*, types::*};
+use crate::{
+    game_frame::{diff::FrameDiff, *},
+    types::*,
+};
 
 use super::tree;
 use crate::{
@@ -14,25 +21,104 @@ use crate::{
     render::{self, draw_map_grid, inventory_bbox, pixel_space_to_tile_space, render_inventory},
 };
 
+pub mod history;
 mod planning;
+#[cfg(test)]
+mod test;
+use self::history::HistoryNode;
 type Point2 = ggez::nalgebra::Point2<f32>;
 
 pub struct GameState {
-    pub history: tree::Zipper<GameFrame, Plan>,
+    pub history: tree::Zipper<HistoryNode, Plan>,
     pub selected: Selection,
     pub current_plan: CachablePlan,
+    // Moves for the turns after `current_plan`, fed into it one turn at a time.
+    pub queues: HashMap<Entity, VecDeque<Move>>,
+    // Refuse to apply a turn while some player has no move planned.
+    pub require_orders: bool,
+    pub show_history: bool,
+    pub bookmarks: Vec<Bookmark>,
+    // The bookmark being typed in, if any.
+    pub bookmark_entry: Option<BookmarkEntry>,
+    // Ticks every time the focus moves, to find the least recently visited frames.
+    pub clock: u64,
+    pub storage: HistoryStorage,
+    pub show_diff: bool,
+    // What the diff overlay compares the focus against, and its path. The focus's parent if there's
+    // none.
+    pub diff_base: Option<(tree::Path, GameFrame)>,
+    // Inverses of the edits made since the focus last moved, most recent last.
+    pub undo_stack: Vec<Edit>,
+    pub redo_stack: Vec<Edit>,
     pub image_map: ImageMap,
 }
 
+// How much of the history tree is kept in memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryStorage {
+    // Every frame.
+    Full,
+    // Only the root and the plans and wishes leading away from it, plus the focus and a cache of the
+    // `cache` most recently visited frames. Any other frame is recomputed by replaying them from its
+    // nearest frame in memory.
+    Replay { cache: usize },
+}
+
+pub const DEFAULT_REPLAY_CACHE: usize = 16;
+
+// A named node in the history tree.
+pub struct Bookmark {
+    pub name: String,
+    pub note: String,
+    pub path: tree::Path,
+}
+
+// A change made within a turn, to the plan being written or to the focus's frame, as opposed to
+// moving around the history. Applying an edit gives back the edit that undoes it.
+#[derive(Clone, Debug)]
+pub enum Edit {
+    SetMove(Entity, Move),
+    // Queues a move for the turn after the player's last planned or queued one.
+    QueueMove(Entity, Move),
+    // Clears the player's planned and queued moves.
+    ClearMoves(Entity),
+    TogglePortal(Point),
+    // Puts the plan being written and the queues back the way they were.
+    RestorePlan(CachablePlan, HashMap<Entity, VecDeque<Move>>),
+    // Wishes an item into a slot of a hypothetical inventory.
+    Wish(Entity, usize, Item),
+    Unwish(Entity, usize),
+}
+
+// Text typed in for a new bookmark: first its name, then its note.
+pub enum BookmarkEntry {
+    Name(String),
+    Note(String, String),
+}
+
 impl GameState {
     pub fn new(ctx: &mut ggez::Context) -> ggez::GameResult<Self> {
-        let image_map = ImageMap::new(ctx)?;
-        Ok(GameState {
-            history: tree::Zipper::new(tree::RoseTree::singleton(GameFrame::new())),
+        Ok(GameState::from_frame(ImageMap::new(ctx)?, GameFrame::new()))
+    }
+    // A game whose history starts at `frame`.
+    pub fn from_frame(image_map: ImageMap, frame: GameFrame) -> Self {
+        GameState {
+            history: tree::Zipper::new(tree::RoseTree::singleton(HistoryNode::new(frame))),
             selected: Selection::Top,
             current_plan: CachablePlan::new(),
+            queues: HashMap::new(),
+            require_orders: false,
+            show_history: false,
+            bookmarks: Vec::new(),
+            bookmark_entry: None,
+            clock: 0,
+            storage: HistoryStorage::Full,
+            show_diff: false,
+            diff_base: None,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
             image_map,
-        })
+        }
     }
 
     pub fn rotate_plan(&mut self) -> Result<(), GameError> {
@@ -40,13 +126,432 @@ impl GameState {
             0 => Err("No future recorded: can't cycle plans")?,
             l => {
                 self.current_plan = match self.current_plan {
-                    CachablePlan::Old(i) => CachablePlan::Old(i.checked_sub(1).unwrap_or(l - 1)),
+                    CachablePlan::Old(i) | CachablePlan::Edited(i, _) => {
+                        CachablePlan::Old(i.checked_sub(1).unwrap_or(l - 1))
+                    }
                     CachablePlan::Novel(_) => CachablePlan::Old(l - 1),
                 };
                 Ok(())
             }
         }
     }
+    // Applies the current plan, moving the history forward a turn, then starts the next plan off
+    // with the front of each queue.
+    pub fn step(&mut self) -> Result<(), GameError> {
+        if self.require_orders && !self.unplanned_players().is_empty() {
+            Err("Not every player has orders")?;
+        }
+        self.current_plan.dedup(&self.history.focus.children);
+        let new_frame = planning::apply_plan(
+            &self.image_map,
+            &self.history.get_focus_val(),
+            &self.current_plan.get(&self.history.focus.children),
+        )?;
+        for (item_type, item_portal_graph) in new_frame.item_portal_graphs.iter() {
+            println!("{:?}", item_type);
+            render_item_graph(&item_portal_graph);
+        }
+        if let Some(failure) = new_frame.failure() {
+            println!("{}", failure);
+        }
+        match self.current_plan {
+            CachablePlan::Novel(ref mut plan) | CachablePlan::Edited(_, ref mut plan) => {
+                let old_plan = mem::replace(plan, Plan::new());
+                self.history.push(HistoryNode::new(new_frame), old_plan);
+                self.current_plan = CachablePlan::new();
+            }
+            CachablePlan::Old(ix) => {
+                self.history.down(ix).expect("Cached plan wasn't there!");
+                self.current_plan = match self.history.focus.children.len() {
+                    0 => CachablePlan::new(),
+                    l => CachablePlan::Old(l - 1),
+                }
+            }
+        }
+        self.arrive()?;
+        let ecs = &self.history.get_focus_val().ecs;
+        self.queues
+            .retain(|&player, queue| ecs.entities.contains_key(player) && !queue.is_empty());
+        if !self.queues.is_empty() {
+            let mut plan = Plan::new();
+            for (&player, queue) in self.queues.iter_mut() {
+                plan.moves
+                    .insert(player, queue.pop_front().expect("Kept an empty queue"));
+            }
+            self.queues.retain(|_, queue| !queue.is_empty());
+            self.current_plan = CachablePlan::Novel(plan);
+            self.current_plan.dedup(&self.history.focus.children);
+        }
+        Ok(())
+    }
+    // Replaces the plan the current one was copied from, recomputing every turn after it. Like a
+    // git rebase, it stops at the first turn that no longer works, in which case the history is left
+    // as it was and the error names that turn.
+    pub fn rebase(&mut self) -> Result<(), GameError> {
+        let (ix, plan) = match self.current_plan {
+            CachablePlan::Edited(ix, ref plan) => (ix, plan.clone()),
+            _ => Err("Only an edited copy of a recorded plan can be rebased")?,
+        };
+        let mut path = self.history.path();
+        path.push(ix);
+        let rebased = history::replay_subtree(
+            &self.image_map,
+            self.history.get_focus_val(),
+            &plan,
+            &self.history.focus.children[ix].1,
+            &mut path,
+        )?;
+        self.history.focus.children[ix] = (plan, rebased);
+        self.current_plan = CachablePlan::Old(ix);
+        self.queues.clear();
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.enforce_storage();
+        Ok(())
+    }
+    pub fn edit(&mut self, edit: Edit) -> Result<(), GameError> {
+        let inverse = self.apply_edit(edit)?;
+        self.undo_stack.push(inverse);
+        self.redo_stack.clear();
+        Ok(())
+    }
+    pub fn undo(&mut self) -> Result<(), GameError> {
+        let inverse = self.undo_stack.pop().ok_or("Nothing to undo")?;
+        let redo = self.apply_edit(inverse)?;
+        self.redo_stack.push(redo);
+        Ok(())
+    }
+    pub fn redo(&mut self) -> Result<(), GameError> {
+        let edit = self.redo_stack.pop().ok_or("Nothing to redo")?;
+        let inverse = self.apply_edit(edit)?;
+        self.undo_stack.push(inverse);
+        Ok(())
+    }
+    // Makes the edit, returning its inverse.
+    fn apply_edit(&mut self, edit: Edit) -> Result<Edit, GameError> {
+        let restore_plan = Edit::RestorePlan(self.current_plan.clone(), self.queues.clone());
+        match edit {
+            Edit::SetMove(player, new_move) => {
+                self.current_plan
+                    .cow(&self.history.focus.children)
+                    .moves
+                    .insert(player, new_move);
+            }
+            Edit::QueueMove(player, new_move) => {
+                self.queues
+                    .entry(player)
+                    .or_insert_with(VecDeque::new)
+                    .push_back(new_move);
+            }
+            Edit::ClearMoves(player) => {
+                self.current_plan
+                    .cow(&self.history.focus.children)
+                    .moves
+                    .remove(&player);
+                self.queues.remove(&player);
+            }
+            Edit::TogglePortal(pt) => {
+                let portals = &mut self.current_plan.cow(&self.history.focus.children).portals;
+                if !portals.remove(&pt) {
+                    portals.insert(pt);
+                }
+            }
+            Edit::RestorePlan(plan, queues) => {
+                self.current_plan = plan;
+                self.queues = queues;
+            }
+            Edit::Wish(player, ix, item) => {
+                self.wish(history::Wish::Add(player, ix, item))?;
+                return Ok(Edit::Unwish(player, ix));
+            }
+            Edit::Unwish(player, ix) => {
+                let item = history::hypothetical_cell(self.history.get_focus_val(), player, ix)?
+                    .ok_or("Nothing to unwish")?
+                    .item
+                    .clone();
+                self.wish(history::Wish::Remove(player, ix, item.clone()))?;
+                return Ok(Edit::Wish(player, ix, item));
+            }
+        }
+        Ok(restore_plan)
+    }
+    // Wishes change a frame that may already have turns after it, so those are recomputed, and the
+    // wish is taken back if any of them no longer works.
+    fn wish(&mut self, wish: history::Wish) -> Result<(), GameError> {
+        let node = self.history.focus.val.clone();
+        let result = self
+            .history
+            .focus
+            .val
+            .wish(wish)
+            .and_then(|()| self.replay_children());
+        if result.is_err() {
+            self.history.focus.val = node;
+        }
+        result
+    }
+    // Recomputes every turn after the focus from its frame. Leaves the history as it was if any of
+    // them no longer works.
+    fn replay_children(&mut self) -> Result<(), GameError> {
+        let path = self.history.path();
+        let focus = &self.history.focus;
+        let children = focus
+            .children
+            .iter()
+            .enumerate()
+            .map(|(i, (plan, child))| {
+                let mut child_path = path.clone();
+                child_path.push(i);
+                let child = history::replay_subtree(
+                    &self.image_map,
+                    &focus.val,
+                    plan,
+                    child,
+                    &mut child_path,
+                )?;
+                Ok((plan.clone(), child))
+            })
+            .collect::<Result<Vec<_>, GameError>>()?;
+        self.history.focus.children = children;
+        self.enforce_storage();
+        Ok(())
+    }
+    // Steps until every queue has run out, stopping at the last good frame if a turn fails.
+    pub fn run_queues(&mut self) -> Result<(), GameError> {
+        while !self.queues.is_empty() {
+            self.step()?;
+        }
+        self.step()
+    }
+    // Moves the history to the previous or next sibling of the focus: the other branches from the
+    // same turn.
+    pub fn go_to_sibling(&mut self, next: bool) -> Result<(), GameError> {
+        self.move_focus(|history| {
+            if next {
+                history.right()?;
+            } else {
+                history.left()?;
+            }
+            Ok(latest_plan(history))
+        })
+    }
+    // Moves the history straight to the node at `path`, anywhere in the tree.
+    pub fn go_to(&mut self, path: &[usize]) -> Result<(), GameError> {
+        self.move_focus(|history| {
+            history.go_to(path)?;
+            Ok(latest_plan(history))
+        })
+    }
+    // Moves the history back a turn, to plan that turn again.
+    pub fn back(&mut self) -> Result<(), GameError> {
+        self.move_focus(|history| Ok(CachablePlan::Old(history.up()?)))
+    }
+    // Moves the history with `move_history`, which gives the plan to start with at the new focus,
+    // then arrives there. If the new focus can't be recomputed, goes back to where it was.
+    fn move_focus<F>(&mut self, move_history: F) -> Result<(), GameError>
+    where
+        F: FnOnce(&mut tree::Zipper<HistoryNode, Plan>) -> Result<CachablePlan, GameError>,
+    {
+        let path = self.history.path();
+        let plan = move_history(&mut self.history)?;
+        let old_plan = mem::replace(&mut self.current_plan, plan);
+        // The queues were planned from the frame we just left.
+        let old_queues = mem::replace(&mut self.queues, HashMap::new());
+        if let Err(err) = self.arrive() {
+            self.history
+                .go_to(&path)
+                .expect("Couldn't go back to the old focus");
+            self.current_plan = old_plan;
+            self.queues = old_queues;
+            return Err(err);
+        }
+        Ok(())
+    }
+    // Starts planning afresh at the focus: the latest recorded plan, if there is one.
+    fn reset_plan(&mut self) {
+        self.current_plan = latest_plan(&self.history);
+        self.queues.clear();
+    }
+    // Call whenever the focus moves. Makes sure the focus's frame is in memory, and evicts others if
+    // there are too many.
+    fn arrive(&mut self) -> Result<(), GameError> {
+        self.materialize_focus()?;
+        self.clock += 1;
+        self.history.focus.val.last_visited = self.clock;
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.enforce_storage();
+        self.validate_selection();
+        Ok(())
+    }
+    // Recomputes an evicted focus by replaying plans from its nearest materialised ancestor.
+    fn materialize_focus(&mut self) -> Result<(), GameError> {
+        if !self.history.focus.val.is_evicted() {
+            return Ok(());
+        }
+        let ancestors: Vec<(&HistoryNode, &Plan)> = self.history.ancestors().collect();
+        let start = ancestors
+            .iter()
+            .rposition(|(node, _)| !node.is_evicted())
+            .expect("The root is never evicted");
+        // Each plan leads to the next ancestor, and the last one to the focus.
+        let mut frame = GameFrame::clone(ancestors[start].0);
+        for (i, (_, plan)) in ancestors.iter().enumerate().skip(start) {
+            let node = match ancestors.get(i + 1) {
+                Some(&(node, _)) => node,
+                None => &self.history.focus.val,
+            };
+            frame = node.recompute(&self.image_map, &frame, plan)?;
+        }
+        self.history.focus.val.restore(frame);
+        Ok(())
+    }
+    // In replay mode, evicts the least recently visited frames until only the cache is left, on top
+    // of the frames that are always kept: the root and the focus.
+    fn enforce_storage(&mut self) {
+        let cache = match self.storage {
+            HistoryStorage::Full => return,
+            HistoryStorage::Replay { cache } => cache,
+        };
+        let mut candidates: Vec<(u64, tree::Path)> = self
+            .history
+            .dfs()
+            .filter(|node| !node.is_focus && !node.path.is_empty() && !node.val.is_evicted())
+            .map(|node| (node.val.last_visited, node.path))
+            .collect();
+        if candidates.len() <= cache {
+            return;
+        }
+        candidates.sort();
+        let excess = candidates.len() - cache;
+        let evicted: HashSet<tree::Path> = candidates
+            .into_iter()
+            .take(excess)
+            .map(|(_, path)| path)
+            .collect();
+        self.history.for_each_mut(|path, node| {
+            if evicted.contains(path) {
+                node.evict();
+            }
+        });
+    }
+    // Deletes the focus and everything after it, moving up to its parent.
+    pub fn delete_branch(&mut self) -> Result<(), GameError> {
+        let deleted = self.history.path();
+        self.history.delete_focus()?;
+        self.move_paths(|path| path_after_delete(path, &deleted));
+        self.reset_plan();
+        self.arrive()
+    }
+    // Deletes every branch that doesn't lead to the focus, keeping the focus's own future.
+    pub fn keep_only_path(&mut self) {
+        let kept = self.history.path();
+        self.history.prune_to_path();
+        self.move_paths(|path| path_after_prune(path, &kept));
+    }
+    // Updates the paths kept outside the history after its shape changes. `new_path` gives where
+    // a node ended up, or None if it's gone, in which case whatever pointed to it is dropped.
+    fn move_paths<F: Fn(&[usize]) -> Option<tree::Path>>(&mut self, new_path: F) {
+        self.bookmarks = mem::replace(&mut self.bookmarks, Vec::new())
+            .into_iter()
+            .filter_map(|mut bookmark| {
+                bookmark.path = new_path(&bookmark.path)?;
+                Some(bookmark)
+            })
+            .collect();
+        self.diff_base = self
+            .diff_base
+            .take()
+            .and_then(|(path, base)| Some((new_path(&path)?, base)));
+    }
+    pub fn add_bookmark(&mut self, name: String, note: String) {
+        self.bookmarks.push(Bookmark {
+            name,
+            note,
+            path: self.history.path(),
+        });
+    }
+    // Jumps to the bookmark after (or before) the one at the focus, or the first one if the focus
+    // isn't bookmarked.
+    pub fn cycle_bookmarks(&mut self, forwards: bool) -> Result<(), GameError> {
+        let len = self.bookmarks.len();
+        if len == 0 {
+            Err("No bookmarks")?;
+        }
+        let path = self.history.path();
+        let ix = match self.bookmarks.iter().position(|b| b.path == path) {
+            Some(ix) if forwards => (ix + 1) % len,
+            Some(ix) => (ix + len - 1) % len,
+            None => 0,
+        };
+        let path = self.bookmarks[ix].path.clone();
+        self.go_to(&path)
+    }
+    // The names of the bookmarks at each bookmarked node.
+    pub fn bookmark_labels(&self) -> HashMap<tree::Path, String> {
+        let mut labels: HashMap<tree::Path, String> = HashMap::new();
+        for bookmark in self.bookmarks.iter() {
+            let label = labels
+                .entry(bookmark.path.clone())
+                .or_insert_with(String::new);
+            if !label.is_empty() {
+                label.push_str(", ");
+            }
+            label.push_str(&bookmark.name);
+        }
+        labels
+    }
+    // The changes from the diff base (or the focus's parent) to the focus.
+    pub fn current_diff(&self) -> Option<FrameDiff> {
+        let (base_path, base) = match self.diff_base {
+            Some((ref path, ref base)) => (path, base),
+            None => {
+                let (parent, _) = self.history.ancestors().last()?;
+                if parent.is_evicted() {
+                    return None;
+                }
+                return Some(FrameDiff::new(parent, self.history.get_focus_val()));
+            }
+        };
+        let path = self.history.path();
+        let split = base_path
+            .iter()
+            .zip(path.iter())
+            .take_while(|(base_ix, ix)| base_ix == ix)
+            .count();
+        // The entities inserted by the frame the two branches split at. If that frame's evicted, an
+        // earlier one will do: it only means fewer entities are matched up by key.
+        let shared = if split == base_path.len() {
+            base.ecs.entities.inserted()
+        } else if split == path.len() {
+            self.history.get_focus_val().ecs.entities.inserted()
+        } else {
+            let (node, _) = self
+                .history
+                .ancestors()
+                .take(split + 1)
+                .filter(|(node, _)| !node.is_evicted())
+                .last()
+                .expect("The root is never evicted");
+            node.ecs.entities.inserted()
+        };
+        Some(FrameDiff::across_branches(
+            base,
+            self.history.get_focus_val(),
+            shared,
+        ))
+    }
+    pub fn unplanned_players(&self) -> Vec<Entity> {
+        let ecs = &self.history.get_focus_val().ecs;
+        let plan = self.current_plan.get(&self.history.focus.children);
+        ecs.players
+            .iter()
+            .map(|(player, _)| player)
+            .filter(|&player| {
+                ecs.entities.contains_key(player) && !plan.moves.contains_key(&player)
+            })
+            .collect()
+    }
     pub fn validate_selection(&mut self) {
         match self.selected {
             Selection::Top => {}
@@ -76,6 +581,13 @@ impl GameState {
         }
     }
     fn left_click_event(&mut self, ctx: &mut ggez::Context, pt: Point2) -> Result<(), GameError> {
+        let panel_bounds = render::history_panel_bbox(ctx);
+        if self.show_history && panel_bounds.contains(pt) {
+            if let Some(path) = render::history_panel_node_at(panel_bounds, &self.history, pt) {
+                self.go_to(&path)?;
+            }
+            return Ok(());
+        }
         match self.selected {
             Selection::Inventory(player_id, _) => {
                 self.selected = inventory_selection(pt, ctx, player_id);
@@ -83,12 +595,11 @@ impl GameState {
             }
             Selection::WishPicker(player_id, ix) => match world_selection(pt, ctx, self) {
                 Selection::GridCell(tile_pt) => {
-                    let frame = self.history.get_focus_val_mut();
-                    let selection = &mut self.selected;
+                    let frame = self.history.get_focus_val();
                     if let Some(item_drop) = frame.items.get_by_position(&tile_pt) {
                         let wished_item = item_drop.item.clone();
-                        frame.wish(player_id, ix, Some(wished_item));
-                        *selection = Selection::Inventory(player_id, Some(ix));
+                        self.edit(Edit::Wish(player_id, ix, wished_item))?;
+                        self.selected = Selection::Inventory(player_id, Some(ix));
                     }
                     Ok(())
                 }
@@ -99,31 +610,21 @@ impl GameState {
                 }
                 _ => panic!("Invalid selection type returned from `world_selection`"),
             },
-            Selection::WishPickerInventoryViewer(player_id, _ix, target_player_id) => {
+            Selection::WishPickerInventoryViewer(player_id, ix, target_player_id) => {
                 match inventory_selection(pt, ctx, target_player_id) {
                     Selection::Inventory(_, None) => Ok(()),
-                    Selection::Inventory(_, Some(ix)) => {
-                        let frame = self.history.get_focus_val_mut();
-                        let selection = &mut self.selected;
-                        let target_player_inventory = frame
+                    Selection::Inventory(_, Some(target_ix)) => {
+                        let target_player_inventory = self
+                            .history
+                            .get_focus_val()
                             .ecs
                             .players
                             .get(target_player_id)
                             .expect("Selection target player id invalid");
-                        if let Some(cell) = target_player_inventory.cells()[ix].as_ref() {
+                        if let Some(cell) = target_player_inventory.cells()[target_ix].as_ref() {
                             let item = cell.item.clone();
-                            let inventory = frame
-                                .ecs
-                                .players
-                                .get_mut(player_id)
-                                .expect("Couldn't find player by id");
-                            match inventory {
-                                Inventory::Actual(_) => panic!("Wishing from actual inventory"),
-                                Inventory::Hypothetical(ref mut hypothetical) => {
-                                    hypothetical.wish(item, ix)?;
-                                    *selection = Selection::Inventory(player_id, Some(ix));
-                                }
-                            }
+                            self.edit(Edit::Wish(player_id, ix, item))?;
+                            self.selected = Selection::Inventory(player_id, Some(ix));
                         }
                         Ok(())
                     }
@@ -138,6 +639,79 @@ impl GameState {
     }
 }
 
+// The latest recorded plan from the focus, if there is one.
+fn latest_plan(history: &tree::Zipper<HistoryNode, Plan>) -> CachablePlan {
+    match history.focus.children.len() {
+        0 => CachablePlan::new(),
+        l => CachablePlan::Old(l - 1),
+    }
+}
+
+// Where the node at `path` ends up once the node at `deleted` is deleted, if it's still there. Its
+// later siblings each move back one.
+fn path_after_delete(path: &[usize], deleted: &[usize]) -> Option<tree::Path> {
+    if path.starts_with(deleted) {
+        return None;
+    }
+    let (&ix, parent) = deleted.split_last().expect("Deleted the root");
+    let mut path = path.to_vec();
+    if path.len() > parent.len() && path.starts_with(parent) && path[parent.len()] > ix {
+        path[parent.len()] -= 1;
+    }
+    Some(path)
+}
+
+// Where the node at `path` ends up once every branch that doesn't lead to or from the node at
+// `kept` is pruned, if it's still there. Every node left on the way to `kept` is an only child.
+fn path_after_prune(path: &[usize], kept: &[usize]) -> Option<tree::Path> {
+    if !kept.starts_with(path) && !path.starts_with(kept) {
+        return None;
+    }
+    let mut path = path.to_vec();
+    let depth = path.len().min(kept.len());
+    for i in path[..depth].iter_mut() {
+        *i = 0;
+    }
+    Some(path)
+}
+
+fn draw_move(
+    ctx: &mut ggez::Context,
+    image_map: &ImageMap,
+    transform: Similarity2<f32>,
+    mv: &Move,
+    position: Point,
+    alpha: f32,
+) -> ggez::GameResult<()> {
+    let angle = |direction| match direction {
+        Direction::Up => 0.,
+        Direction::Left => 1.5 * PI,
+        Direction::Down => PI,
+        Direction::Right => 0.5 * PI,
+    };
+    let (image, rotation) = match *mv {
+        Move::Direction(direction) => (&image_map.move_arrow, angle(direction)),
+        Move::Throw(_, direction) => (&image_map.throw_icon, angle(direction)),
+        Move::Jump => (&image_map.jump_icon, 0.),
+        Move::PickUp(_) => (&image_map.pick_up_icon, 0.),
+        Move::Drop(_) => (&image_map.drop_icon, 0.),
+        Move::Use => (&image_map.use_icon, 0.),
+        Move::Wait => (&image_map.wait_icon, 0.),
+        Move::Give(_, _) => (&image_map.give_icon, 0.),
+    };
+    let dest = transform
+        * (nalgebra::convert::<nalgebra::Point2<i32>, nalgebra::Point2<f32>>(position)
+            + Vector2::new(0.5, 0.5));
+    image.draw(
+        ctx,
+        DrawParam::new()
+            .dest(dest)
+            .offset([0.5, 0.5])
+            .rotation(rotation)
+            .color(Color::new(1., 1., 1., alpha)),
+    )
+}
+
 fn world_selection(pt: Point2, ctx: &ggez::Context, game_state: &GameState) -> Selection {
     let world_space_pt: Point = pixel_space_to_tile_space(pt, graphics::screen_coordinates(ctx))
         .expect("Somehow clicked outside window");
@@ -176,14 +750,47 @@ impl event::EventHandler for GameState {
             println!("{}", msg)
         }
     }
+    fn text_input_event(&mut self, _ctx: &mut ggez::Context, character: char) {
+        if character.is_control() {
+            return;
+        }
+        match self.bookmark_entry {
+            Some(BookmarkEntry::Name(ref mut text))
+            | Some(BookmarkEntry::Note(_, ref mut text)) => text.push(character),
+            None => {}
+        }
+    }
     fn key_down_event(
         &mut self,
         _ctx: &mut ggez::Context,
         key: event::KeyCode,
-        _keymods: event::KeyMods,
+        keymods: event::KeyMods,
         _repeat: bool,
     ) {
         use self::event::KeyCode;
+        if let Some(entry) = self.bookmark_entry.take() {
+            self.bookmark_entry = match (key, entry) {
+                (KeyCode::Escape, _) => None,
+                (KeyCode::Return, BookmarkEntry::Name(name)) => {
+                    Some(BookmarkEntry::Note(name, String::new()))
+                }
+                (KeyCode::Return, BookmarkEntry::Note(name, note)) => {
+                    self.add_bookmark(name, note);
+                    None
+                }
+                (KeyCode::Back, BookmarkEntry::Name(mut name)) => {
+                    name.pop();
+                    Some(BookmarkEntry::Name(name))
+                }
+                (KeyCode::Back, BookmarkEntry::Note(name, mut note)) => {
+                    note.pop();
+                    Some(BookmarkEntry::Note(name, note))
+                }
+                (_, entry) => Some(entry),
+            };
+            return;
+        }
+        let mut edit_result = Ok(());
         match self.selected {
             Selection::Player(player_id) => {
                 enum Update {
@@ -196,21 +803,30 @@ impl event::EventHandler for GameState {
                     KeyCode::S => Update::Move(Move::Direction(Direction::Down)),
                     KeyCode::D => Update::Move(Move::Direction(Direction::Right)),
                     KeyCode::Q => Update::Move(Move::Jump),
-                    KeyCode::G => Update::Move(Move::PickUp),
+                    KeyCode::G => Update::Move(Move::PickUp(None)),
+                    KeyCode::Key1 => Update::Move(Move::PickUp(Some(0))),
+                    KeyCode::Key2 => Update::Move(Move::PickUp(Some(1))),
+                    KeyCode::Key3 => Update::Move(Move::PickUp(Some(2))),
+                    KeyCode::Key4 => Update::Move(Move::PickUp(Some(3))),
+                    KeyCode::Key5 => Update::Move(Move::PickUp(Some(4))),
+                    KeyCode::E => Update::Move(Move::Use),
+                    KeyCode::Period => Update::Move(Move::Wait),
                     keycode => Update::Other(keycode),
                 };
                 match update {
                     Update::Move(new_move) => {
-                        self.current_plan
-                            .cow(&self.history.focus.children)
-                            .moves
-                            .insert(player_id, new_move);
+                        let plan = self.current_plan.get(&self.history.focus.children);
+                        let edit = if keymods.contains(event::KeyMods::SHIFT)
+                            && plan.moves.contains_key(&player_id)
+                        {
+                            Edit::QueueMove(player_id, new_move)
+                        } else {
+                            Edit::SetMove(player_id, new_move)
+                        };
+                        edit_result = self.edit(edit);
                     }
                     Update::Other(KeyCode::Space) => {
-                        self.current_plan
-                            .cow(&self.history.focus.children)
-                            .moves
-                            .remove(&player_id);
+                        edit_result = self.edit(Edit::ClearMoves(player_id));
                     }
                     Update::Other(KeyCode::I) => {
                         self.selected = Selection::Inventory(player_id, None);
@@ -220,43 +836,64 @@ impl event::EventHandler for GameState {
             }
             Selection::GridCell(pt) => {
                 if let KeyCode::Q = key {
-                    if self
-                        .current_plan
-                        .get(&self.history.focus.children)
-                        .portals
-                        .contains(&pt)
-                    {
-                        self.current_plan
-                            .cow(&self.history.focus.children)
-                            .portals
-                            .remove(&pt);
-                    } else {
-                        self.current_plan
-                            .cow(&self.history.focus.children)
-                            .portals
-                            .insert(pt);
-                    }
+                    edit_result = self.edit(Edit::TogglePortal(pt));
                 }
             }
             Selection::Inventory(player_id, Some(ix)) => match key {
                 KeyCode::T => {
-                    self.current_plan
-                        .cow(&self.history.focus.children)
-                        .moves
-                        .insert(player_id, Move::Drop(ix));
+                    edit_result = self.edit(Edit::SetMove(player_id, Move::Drop(ix)));
+                }
+                KeyCode::W | KeyCode::A | KeyCode::S | KeyCode::D => {
+                    let direction = match key {
+                        KeyCode::W => Direction::Up,
+                        KeyCode::A => Direction::Left,
+                        KeyCode::S => Direction::Down,
+                        _ => Direction::Right,
+                    };
+                    let ecs = &self.history.get_focus_val().ecs;
+                    let position = *ecs
+                        .positions
+                        .get(player_id)
+                        .expect("Player without position");
+                    // Shift throws the item that way, otherwise it's given to the player there.
+                    let new_move = if keymods.contains(event::KeyMods::SHIFT) {
+                        Some(Move::Throw(ix, direction))
+                    } else {
+                        player_at(ecs, position + direction.delta())
+                            .map(|receiver| Move::Give(ix, receiver))
+                    };
+                    edit_result = match new_move {
+                        Some(new_move) => self.edit(Edit::SetMove(player_id, new_move)),
+                        None => Err("No player there to give to".into()),
+                    };
+                }
+                // Gives the item to another player on the same tile.
+                KeyCode::G => {
+                    let ecs = &self.history.get_focus_val().ecs;
+                    let position = *ecs
+                        .positions
+                        .get(player_id)
+                        .expect("Player without position");
+                    edit_result = match other_player_at(ecs, position, player_id) {
+                        Some(receiver) => {
+                            self.edit(Edit::SetMove(player_id, Move::Give(ix, receiver)))
+                        }
+                        None => Err("No one else here to give to".into()),
+                    };
                 }
                 KeyCode::Equals => {
-                    let game_frame = self.history.get_focus_val_mut();
-                    let wish_result = game_frame.wish(player_id, ix, None);
-                    if let FrameWishResult::NoItem = wish_result {
-                        self.selected = Selection::WishPicker(player_id, ix);
+                    let frame = self.history.get_focus_val();
+                    match hypothetical_cell(frame, player_id, ix) {
+                        Ok(Some(cell)) => {
+                            let item = cell.item.clone();
+                            edit_result = self.edit(Edit::Wish(player_id, ix, item));
+                        }
+                        Ok(None) => self.selected = Selection::WishPicker(player_id, ix),
+                        Err(err) => edit_result = Err(err),
                     }
                 }
                 KeyCode::Minus => {
-                    let game_frame = self.history.get_focus_val_mut();
-                    if let Err(err) = game_frame.unwish(player_id, ix) {
-                        println!("{}", err)
-                    }
+                    edit_result = self.edit(Edit::Unwish(player_id, ix));
                 }
                 _ => {}
             },
@@ -265,6 +902,9 @@ impl event::EventHandler for GameState {
             | Selection::WishPicker(_, _)
             | Selection::WishPickerInventoryViewer(_, _, _) => {}
         }
+        if let Err(err) = edit_result {
+            println!("{}", err);
+        }
 
         match key {
             KeyCode::Tab => {
@@ -272,40 +912,81 @@ impl event::EventHandler for GameState {
                     println!("{}", err);
                 }
             }
-            KeyCode::Back => match self.history.up() {
-                Ok(ix) => {
-                    self.current_plan = CachablePlan::Old(ix);
-                    self.validate_selection();
+            KeyCode::Back => {
+                if let Err(err) = self.back() {
+                    println!("{}", err);
                 }
-                Err(err) => println!("{}", err),
-            },
-            KeyCode::Return => match planning::apply_plan(
-                &self.image_map,
-                &self.history.get_focus_val(),
-                &self.current_plan.get(&self.history.focus.children),
-            ) {
-                Err(err) => println!("{}", err),
-                Ok(new_frame) => {
-                    for (item_type, item_portal_graph) in new_frame.item_portal_graphs.iter() {
-                        println!("{:?}", item_type);
-                        render_item_graph(&item_portal_graph);
-                    }
-                    match self.current_plan {
-                        CachablePlan::Novel(ref mut plan) => {
-                            let old_plan = std::mem::replace(plan, Plan::new());
-                            self.history.push(new_frame, old_plan);
-                        }
-                        CachablePlan::Old(ix) => {
-                            self.history.down(ix).expect("Cached plan wasn't there!");
-                            self.current_plan = match self.history.focus.children.len() {
-                                0 => CachablePlan::new(),
-                                l => CachablePlan::Old(l - 1),
-                            }
-                        }
-                    }
-                    self.validate_selection();
+            }
+            KeyCode::Delete => {
+                if let Err(err) = self.delete_branch() {
+                    println!("{}", err);
                 }
-            },
+            }
+            KeyCode::K => self.keep_only_path(),
+            KeyCode::M => {
+                self.storage = match self.storage {
+                    HistoryStorage::Full => HistoryStorage::Replay {
+                        cache: DEFAULT_REPLAY_CACHE,
+                    },
+                    HistoryStorage::Replay { .. } => HistoryStorage::Full,
+                };
+                self.enforce_storage();
+                println!("History storage: {:?}", self.storage);
+            }
+            KeyCode::Return => {
+                let result = if keymods.contains(event::KeyMods::SHIFT) {
+                    self.rebase()
+                } else {
+                    self.step()
+                };
+                if let Err(err) = result {
+                    println!("{}", err);
+                }
+            }
+            KeyCode::H => self.show_history = !self.show_history,
+            KeyCode::Left | KeyCode::Right => {
+                if let Err(err) = self.go_to_sibling(key == KeyCode::Right) {
+                    println!("{}", err);
+                }
+            }
+            KeyCode::Z | KeyCode::Y if keymods.contains(event::KeyMods::CTRL) => {
+                let result = if key == KeyCode::Z {
+                    self.undo()
+                } else {
+                    self.redo()
+                };
+                if let Err(err) = result {
+                    println!("{}", err);
+                }
+            }
+            KeyCode::F => {
+                if keymods.contains(event::KeyMods::SHIFT) {
+                    self.diff_base = match self.diff_base {
+                        Some(_) => None,
+                        None => Some((
+                            self.history.path(),
+                            GameFrame::clone(self.history.get_focus_val()),
+                        )),
+                    };
+                } else {
+                    self.show_diff = !self.show_diff;
+                }
+            }
+            KeyCode::F2 => self.bookmark_entry = Some(BookmarkEntry::Name(String::new())),
+            KeyCode::PageDown | KeyCode::PageUp => {
+                if let Err(err) = self.cycle_bookmarks(key == KeyCode::PageDown) {
+                    println!("{}", err);
+                }
+            }
+            KeyCode::O => {
+                self.require_orders = !self.require_orders;
+                println!("Require orders for every player: {}", self.require_orders);
+            }
+            KeyCode::R => {
+                if let Err(err) = self.run_queues() {
+                    println!("{}", err);
+                }
+            }
             KeyCode::Escape => self.selected.pop(),
             _ => {}
         }
@@ -319,6 +1000,8 @@ impl event::EventHandler for GameState {
         graphics::clear(ctx, white);
         let frame = self.history.get_focus_val();
         render::ecs(ctx, &frame.ecs)?;
+        render::edge_walls(ctx, &frame.ecs)?;
+        render::guard_vision(ctx, &frame.ecs)?;
         draw_map_grid(ctx, black)?;
         // TODO: this should be over entities with positions and plans. IIRC the ECS talk gave some
         // advice on how to structure stuff like this: ideally this would be a "system" that we'd
@@ -331,35 +1014,21 @@ impl event::EventHandler for GameState {
                 .moves
                 .get(&player_id)
             {
-                let position = *frame
+                let mut position = *frame
                     .ecs
                     .positions
                     .get(player_id)
                     .expect("Player without positon");
-                let (image, rotation) = match *mv {
-                    Move::Direction(ref dir) => {
-                        let angle = match *dir {
-                            Direction::Up => 0.,
-                            Direction::Left => 1.5 * PI,
-                            Direction::Down => PI,
-                            Direction::Right => 0.5 * PI,
-                        };
-                        (&self.image_map.move_arrow, angle)
+                draw_move(ctx, &self.image_map, transform, mv, position, 1.)?;
+                // Queued moves are drawn fainter, along the path the player would take.
+                let mut previous = mv;
+                for mv in self.queues.get(&player_id).into_iter().flatten() {
+                    if let Move::Direction(direction) = *previous {
+                        position += direction.delta();
                     }
-                    Move::Jump => (&self.image_map.jump_icon, 0.),
-                    Move::PickUp => (&self.image_map.pick_up_icon, 0.),
-                    Move::Drop(_) => (&self.image_map.drop_icon, 0.),
-                };
-                let dest = transform
-                    * (nalgebra::convert::<nalgebra::Point2<i32>, nalgebra::Point2<f32>>(position)
-                        + Vector2::new(0.5, 0.5));
-                image.draw(
-                    ctx,
-                    DrawParam::new()
-                        .dest(dest)
-                        .offset([0.5, 0.5])
-                        .rotation(rotation),
-                )?;
+                    draw_move(ctx, &self.image_map, transform, mv, position, 0.5)?;
+                    previous = mv;
+                }
             }
             for pt in &self.current_plan.get(&self.history.focus.children).portals {
                 self.image_map.jump_icon.draw(
@@ -384,17 +1053,61 @@ impl event::EventHandler for GameState {
                 ),
             )?;
         }
-        for (_, item_drop) in self.history.get_focus_val().items.iter() {
-            item_drop.item.image(&self.image_map).draw(
+        // Only the top of each stack is drawn, with a count if there's more underneath.
+        for (position, stack) in self.history.get_focus_val().items.stacks() {
+            let dest = transform
+                * nalgebra::convert::<nalgebra::Point2<i32>, nalgebra::Point2<f32>>(position);
+            let top = stack.last().expect("Empty item stack");
+            top.item
+                .image(&self.image_map)
+                .draw(ctx, DrawParam::new().dest(dest))?;
+            if stack.len() > 1 {
+                graphics::draw(
+                    ctx,
+                    &graphics::Text::new(stack.len().to_string()),
+                    DrawParam::new()
+                        .dest(dest + Vector2::new(5., 5.))
+                        .color(black),
+                )?;
+            }
+        }
+        for player in self.unplanned_players() {
+            let position = *frame
+                .ecs
+                .positions
+                .get(player)
+                .expect("Player without positon");
+            self.image_map.unplanned_icon.draw(
+                ctx,
+                DrawParam::new().dest(
+                    transform
+                        * nalgebra::convert::<nalgebra::Point2<i32>, nalgebra::Point2<f32>>(
+                            position,
+                        ),
+                ),
+            )?;
+        }
+        for death in self.history.get_focus_val().deaths.iter() {
+            self.image_map.death.draw(
                 ctx,
                 DrawParam::new().dest(
                     transform
                         * nalgebra::convert::<nalgebra::Point2<i32>, nalgebra::Point2<f32>>(
-                            item_drop.position,
+                            death.position,
                         ),
                 ),
             )?;
         }
+        if let Some(failure) = self.history.get_focus_val().failure() {
+            let red: Color = (204, 0, 0).into();
+            graphics::draw(
+                ctx,
+                &graphics::Text::new(failure.into_owned()),
+                DrawParam::new()
+                    .dest(Point2::new(x0 + 5., y0 + 5.))
+                    .color(red),
+            )?;
+        }
         match self.selected {
             Selection::Top => {}
             Selection::GridCell(pt) => {
@@ -445,6 +1158,63 @@ impl event::EventHandler for GameState {
                 render_inventory(inventory, ctx, &self.image_map, &None)?;
             }
         }
+        if self.show_diff {
+            if let Some(diff) = self.current_diff() {
+                render::frame_diff(ctx, &diff)?;
+            }
+        }
+        if self.show_history {
+            render::history_panel(ctx, &self.history, &self.bookmark_labels())?;
+        }
+        // Along the bottom: the bookmark being typed in, or the notes on the focus's bookmarks
+        // followed by the stack under the selection, numbered for the pick-up keys.
+        let path = self.history.path();
+        let status = match self.bookmark_entry {
+            Some(BookmarkEntry::Name(ref name)) => format!("Bookmark name: {}_", name),
+            Some(BookmarkEntry::Note(ref name, ref note)) => {
+                format!("Note for {}: {}_", name, note)
+            }
+            None => {
+                let frame = self.history.get_focus_val();
+                let stack_position = match self.selected {
+                    Selection::GridCell(pt) => Some(pt),
+                    Selection::Player(player_id) => frame.ecs.positions.get(player_id).cloned(),
+                    _ => None,
+                };
+                let stack = stack_position.map_or_else(Vec::new, |pt| frame.items.stack_at(&pt));
+                let stack_lines = if stack.len() > 1 {
+                    stack
+                        .iter()
+                        .enumerate()
+                        .map(|(ix, drop)| match ix {
+                            0..=4 => format!("{}: {:?}", ix + 1, drop.item),
+                            _ => format!("   {:?}", drop.item),
+                        })
+                        .collect()
+                } else {
+                    vec![]
+                };
+                self.bookmarks
+                    .iter()
+                    .filter(|bookmark| bookmark.path == path)
+                    .map(|bookmark| format!("{}: {}", bookmark.name, bookmark.note))
+                    .chain(stack_lines)
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        };
+        if !status.is_empty() {
+            let graphics::Rect { h, .. } = graphics::screen_coordinates(ctx);
+            let text = graphics::Text::new(status);
+            let text_height = text.height(ctx) as f32;
+            graphics::draw(
+                ctx,
+                &text,
+                DrawParam::new()
+                    .dest(Point2::new(x0 + 5., y0 + h - text_height - 5.))
+                    .color(black),
+            )?;
+        }
         graphics::present(ctx)
     }
 }
@@ -0,0 +1,191 @@
+use super::apply_plan;
+use crate::{
+    game_frame::GameFrame,
+    types::{
+        Counter, Direction, DrawRef, Entity, EventListener, Gate, Group, Guard, ImageMap,
+        Inventory, MapElement, Move, Movement, Patrol, Plan, Point, ECS,
+    },
+};
+use enumset::EnumSet;
+use ggez::nalgebra::Point2;
+use slotmap::{HopSlotMap, SecondaryMap};
+use std::collections::{HashMap, HashSet};
+use test::{black_box, Bencher};
+
+new_key_type! { struct OldEntity; }
+
+macro_rules! old_ecs {
+    ($($table:ident: $component:ty,)*) => {
+        // The ECS as it was before its tables were made persistent: a slot map of entities and a
+        // `SecondaryMap` for each component, all of them copied whenever the ECS is. It's only ever
+        // cloned.
+        #[allow(dead_code)]
+        #[derive(Clone)]
+        struct OldEcs {
+            entities: HopSlotMap<OldEntity, ()>,
+            declared_counters: HashSet<Counter>,
+            $($table: SecondaryMap<OldEntity, $component>,)*
+        }
+
+        impl OldEcs {
+            fn new(ecs: &ECS) -> Self {
+                let mut entities = HopSlotMap::with_key();
+                let keys: HashMap<Entity, OldEntity> = ecs
+                    .entities
+                    .iter()
+                    .map(|(entity, _)| (entity, entities.insert(())))
+                    .collect();
+                OldEcs {
+                    entities,
+                    declared_counters: ecs.declared_counters.clone(),
+                    $($table: {
+                        let mut table = SecondaryMap::new();
+                        for (entity, component) in ecs.$table.iter() {
+                            table.insert(keys[&entity], component.clone());
+                        }
+                        table
+                    },)*
+                }
+            }
+        }
+    };
+}
+
+old_ecs! {
+    images: DrawRef,
+    positions: Point,
+    event_listeners: Vec<EventListener>,
+    disabled_event_groups: EnumSet<Group>,
+    counters: HashMap<Counter, i64>,
+    players: Inventory,
+    movement: Movement,
+    patrols: Patrol,
+    guards: Guard,
+    opaque: (),
+    carriers: (),
+    gates: Gate,
+    teleporters: Point,
+    conveyors: Direction,
+    ice: (),
+    one_ways: Direction,
+    edge_walls: Direction,
+}
+
+// A level much bigger than any real one: a walled 40x40 room full of pillars, with a few players
+// along its left wall.
+fn large_frame(image_map: &ImageMap) -> (GameFrame, Vec<Entity>) {
+    let mut frame = GameFrame::new();
+    for x in 0..40 {
+        for y in 0..40 {
+            let border = x == 0 || x == 39 || y == 0 || y == 39;
+            let pillar = x > 4 && x % 4 == 0 && y % 4 == 2;
+            if border || pillar {
+                MapElement::Wall.add(image_map, Point2::new(x, y), &mut frame.ecs);
+            }
+        }
+    }
+    let players = (1..8)
+        .map(|y| {
+            frame
+                .insert_player(image_map, Point2::new(1, y * 4))
+                .expect("Error inserting player")
+        })
+        .collect();
+    (frame, players)
+}
+
+fn move_all(players: &[Entity], direction: Direction) -> Plan {
+    let mut plan = Plan::new();
+    for &player in players {
+        plan.moves.insert(player, Move::Direction(direction));
+    }
+    plan
+}
+
+#[bench]
+fn bench_clone_frame(b: &mut Bencher) {
+    let image_map = ImageMap::mock();
+    let (frame, _) = large_frame(&image_map);
+    b.iter(|| black_box(frame.clone()));
+}
+
+#[bench]
+fn bench_clone_ecs(b: &mut Bencher) {
+    let image_map = ImageMap::mock();
+    let (frame, _) = large_frame(&image_map);
+    b.iter(|| black_box(frame.ecs.clone()));
+}
+
+// The same ECS stored the old way, for comparison with `bench_clone_ecs`.
+#[bench]
+fn bench_clone_old_ecs(b: &mut Bencher) {
+    let image_map = ImageMap::mock();
+    let (frame, _) = large_frame(&image_map);
+    let old_ecs = OldEcs::new(&frame.ecs);
+    b.iter(|| black_box(old_ecs.clone()));
+}
+
+#[bench]
+fn bench_apply_plan(b: &mut Bencher) {
+    let image_map = ImageMap::mock();
+    let (frame, players) = large_frame(&image_map);
+    let plan = move_all(&players, Direction::Right);
+    b.iter(|| apply_plan(&image_map, &frame, &plan).expect("Couldn't apply plan"));
+}
+
+// `apply_plan` starts by copying its frame, which used to mean copying the whole ECS. Approximates
+// the old cost, for comparison with `bench_apply_plan`, by paying for that copy on top.
+#[bench]
+fn bench_apply_plan_with_old_clone(b: &mut Bencher) {
+    let image_map = ImageMap::mock();
+    let (frame, players) = large_frame(&image_map);
+    let old_ecs = OldEcs::new(&frame.ecs);
+    let plan = move_all(&players, Direction::Right);
+    b.iter(|| {
+        black_box(old_ecs.clone());
+        apply_plan(&image_map, &frame, &plan).expect("Couldn't apply plan")
+    });
+}
+
+// A hundred turns of history, kept the way the history tree keeps them.
+#[bench]
+fn bench_history(b: &mut Bencher) {
+    let image_map = ImageMap::mock();
+    let (frame, players) = large_frame(&image_map);
+    let plans = [
+        move_all(&players, Direction::Right),
+        move_all(&players, Direction::Left),
+    ];
+    b.iter(|| {
+        let mut frames = vec![frame.clone()];
+        for turn in 0..100 {
+            let last = frames.last().expect("History can't be empty");
+            let next = apply_plan(&image_map, last, &plans[turn % 2]).expect("Couldn't apply plan");
+            frames.push(next);
+        }
+        black_box(frames)
+    });
+}
+
+// `bench_history`, with an ECS stored the old way copied and kept alongside every turn, as the old
+// history did.
+#[bench]
+fn bench_history_with_old_clones(b: &mut Bencher) {
+    let image_map = ImageMap::mock();
+    let (frame, players) = large_frame(&image_map);
+    let old_ecs = OldEcs::new(&frame.ecs);
+    let plans = [
+        move_all(&players, Direction::Right),
+        move_all(&players, Direction::Left),
+    ];
+    b.iter(|| {
+        let mut frames = vec![(frame.clone(), old_ecs.clone())];
+        for turn in 0..100 {
+            let (last, last_old_ecs) = frames.last().expect("History can't be empty");
+            let next = apply_plan(&image_map, last, &plans[turn % 2]).expect("Couldn't apply plan");
+            let next_old_ecs = last_old_ecs.clone();
+            frames.push((next, next_old_ecs));
+        }
+        black_box(frames)
+    });
+}
@@ -1,34 +1,42 @@
-use petgraph::{
-    graphmap::GraphMap,
-    visit::{self, IntoNeighbors},
-};
+use petgraph::visit::{self, IntoNeighbors};
 
 use crate::{
-    game_frame::GameFrame,
+    game_frame::{GameFrame, ScheduledAction},
     portal_graph::{
         find_latest_held, find_latest_held_index, render_item_graph, signed_wish,
         ItemPortalGraphNode, PlayerPortalGraphNode,
     },
     types::{
-        inner_join, player_at, Action, Direction, EventTrigger, EventTriggerModifier, GameError,
-        HypotheticalInventory, ImageMap, Inventory, ItemDrop, Move, MovementType, Plan, Portal,
+        blocked_at, conveyor_at, inner_join, move_blocked, on_ice, player_at, teleport_destination,
+        throw_landing, visible_tiles, Action, Counter, Direction, Entity, EventTrigger,
+        EventTriggerModifier, GameError, Gate, HypotheticalInventory, Id, ImageMap, Inventory,
+        ItemDrop, Move, MovementType, Plan, Point, Portal, Priority, ECS,
     },
 };
-use enum_map::EnumMap;
 use enumset::EnumSet;
-use ggez::nalgebra;
-use std::{cmp::min, iter};
+use std::{cmp::min, collections::HashSet, iter};
 
 pub fn apply_plan(
     image_map: &ImageMap,
     initial_frame: &GameFrame,
     plan: &Plan,
 ) -> Result<GameFrame, GameError> {
+    if let Some(failure) = initial_frame.failure() {
+        Err(failure)?;
+    }
     let mut out = initial_frame.clone();
 
     // Apply the plan
 
-    for (&entity, mv) in plan.moves.iter() {
+    // Moves and portals go in a fixed order, since they can hand out ids and create entities, and
+    // equal plans should always give equal frames.
+    let mut moves: Vec<(&Entity, &Move)> = plan.moves.iter().collect();
+    moves.sort_by_key(|&(&entity, _)| entity);
+    let mut portals: Vec<Point> = plan.portals.iter().copied().collect();
+    portals.sort_by_key(|pt| (pt.x, pt.y));
+
+    let mut used_positions = HashSet::new();
+    for (&entity, mv) in moves {
         if !out.ecs.entities.contains_key(entity) {
             panic!(
                 "Plan for non-existant entity {:?} : ECS: {:#?}, Plan: {:#?}",
@@ -37,6 +45,20 @@ pub fn apply_plan(
         }
         match mv {
             Move::Direction(direction) => {
+                let position = *out
+                    .ecs
+                    .positions
+                    .get(entity)
+                    .expect("Entity with no position attempted to move");
+                let sliding = on_ice(&out.ecs, position)
+                    && out
+                        .ecs
+                        .movement
+                        .get(entity)
+                        .map_or(false, |movement| movement.direction.is_some());
+                if sliding {
+                    Err("Can't change direction while sliding on ice")?;
+                }
                 let movement = out
                     .ecs
                     .movement
@@ -60,7 +82,7 @@ pub fn apply_plan(
                 // Next, we find the player we're merging into: "post_player"
                 let mut last_edge = None;
                 visit::depth_first_search(
-                    &out.player_portal_graph,
+                    &*out.player_portal_graph,
                     iter::once(PlayerPortalGraphNode::Portal(portal.id)),
                     |e| {
                         if let visit::DfsEvent::TreeEdge(n1, n2) = e {
@@ -138,7 +160,7 @@ pub fn apply_plan(
                 );
                 // Check that the player can still reach end (no loops)
                 if !petgraph::algo::has_path_connecting(
-                    &out.player_portal_graph,
+                    &*out.player_portal_graph,
                     PlayerPortalGraphNode::Portal(portal.id),
                     PlayerPortalGraphNode::End,
                     None,
@@ -170,7 +192,7 @@ pub fn apply_plan(
                 for (item, item_portal_graph) in out.item_portal_graphs.iter_mut() {
                     if let Some(origin_node) = find_latest_held(item_portal_graph, prior_player) {
                         let filtered =
-                            visit::EdgeFiltered::from_fn(&*item_portal_graph, |(_, _, &w)| w != 0);
+                            visit::EdgeFiltered::from_fn(&**item_portal_graph, |(_, _, &w)| w != 0);
                         let mut dfs = visit::Dfs::new(&filtered, origin_node);
                         let mut found_sink = false;
                         while let Some(node) = dfs.next(&filtered) {
@@ -188,7 +210,7 @@ pub fn apply_plan(
                 // Finally, remove prior_player from the ecs.
                 out.ecs.entities.remove(prior_player);
             }
-            Move::PickUp => {
+            Move::PickUp(stack_ix) => {
                 let inventory = out
                     .ecs
                     .players
@@ -201,14 +223,11 @@ pub fn apply_plan(
                     .expect("Entity with no position attempted to pick up");
                 let item_drop = out
                     .items
-                    .remove_by_position(position)
+                    .remove_by_position(position, *stack_ix)
                     .ok_or("Couln't pick up: no item")?;
                 let item = item_drop.item;
                 let prior_item_count = inventory.count_items().get(&item).map_or(0, |x| *x);
-                let item_portal_graph = out
-                    .item_portal_graphs
-                    .entry(item.clone())
-                    .or_insert_with(GraphMap::new);
+                let item_portal_graph = out.item_portal_graphs.entry(item.clone()).or_default();
                 let old_held_ix = find_latest_held_index(item_portal_graph, entity).unwrap_or(0);
                 let new_held_ix = old_held_ix + 1;
                 item_portal_graph.add_edge(
@@ -223,26 +242,27 @@ pub fn apply_plan(
                 );
                 inventory.insert(&item)?;
             }
-            Move::Drop(item_ix) => {
-                let inventory = out
-                    .ecs
-                    .players
-                    .get_mut(entity)
-                    .expect("Entity with no inventory attempted to drop");
+            Move::Drop(item_ix) | Move::Throw(item_ix, _) => {
                 let position = *out
                     .ecs
                     .positions
                     .get(entity)
                     .expect("Entity with no position attempted to drop");
+                let landing = match mv {
+                    Move::Throw(_, direction) => throw_landing(&out.ecs, position, *direction),
+                    _ => position,
+                };
+                let inventory = out
+                    .ecs
+                    .players
+                    .get_mut(entity)
+                    .expect("Entity with no inventory attempted to drop");
                 let item = inventory.drop(*item_ix)?;
                 let remaining_item_count = inventory.count_items().get(&item).map_or(0, |x| *x);
-                let item_drop = ItemDrop::new(item.clone(), position);
+                let item_drop = ItemDrop::new(&mut out.ids, item.clone(), landing);
                 let item_drop_id = item_drop.id;
                 out.items.insert(item_drop)?;
-                let item_portal_graph = out
-                    .item_portal_graphs
-                    .entry(item.clone())
-                    .or_insert_with(GraphMap::new);
+                let item_portal_graph = out.item_portal_graphs.entry(item.clone()).or_default();
                 let latest_held_index =
                     find_latest_held_index(item_portal_graph, entity).unwrap_or(0);
                 let latest_held = ItemPortalGraphNode::Held(entity, latest_held_index);
@@ -254,12 +274,69 @@ pub fn apply_plan(
                 let next_held = ItemPortalGraphNode::Held(entity, latest_held_index + 1);
                 item_portal_graph.add_edge(latest_held, next_held, remaining_item_count);
             }
+            Move::Use => {
+                let position = *out
+                    .ecs
+                    .positions
+                    .get(entity)
+                    .expect("Entity with no position attempted to use");
+                used_positions.insert(position);
+            }
+            Move::Give(item_ix, receiver) => {
+                let receiver = *receiver;
+                if receiver == entity {
+                    Err("Can't give an item to yourself")?;
+                }
+                if !out.ecs.entities.contains_key(receiver)
+                    || !out.ecs.players.contains_key(receiver)
+                {
+                    Err("Can only give items to players")?;
+                }
+                let offset = out.ecs.positions[receiver] - out.ecs.positions[entity];
+                if offset.x.abs() + offset.y.abs() > 1 {
+                    Err("Can only give items to adjacent players")?;
+                }
+                let inventory = out
+                    .ecs
+                    .players
+                    .get_mut(entity)
+                    .expect("Entity with no inventory attempted to give");
+                let item = inventory.drop(*item_ix)?;
+                let remaining_item_count = inventory.count_items().get(&item).map_or(0, |x| *x);
+                let receiver_inventory = out
+                    .ecs
+                    .players
+                    .get_mut(receiver)
+                    .expect("Checked that the receiver is a player");
+                let prior_item_count = receiver_inventory
+                    .count_items()
+                    .get(&item)
+                    .map_or(0, |x| *x);
+                receiver_inventory.insert(&item)?;
+                let item_portal_graph = out.item_portal_graphs.entry(item.clone()).or_default();
+                let giver_held_index =
+                    find_latest_held_index(item_portal_graph, entity).unwrap_or(0);
+                let receiver_held_index =
+                    find_latest_held_index(item_portal_graph, receiver).unwrap_or(0);
+                let giver_held = ItemPortalGraphNode::Held(entity, giver_held_index);
+                let receiver_held = ItemPortalGraphNode::Held(receiver, receiver_held_index);
+                let receiver_next_held =
+                    ItemPortalGraphNode::Held(receiver, receiver_held_index + 1);
+                item_portal_graph.add_edge(giver_held, receiver_next_held, 1);
+                item_portal_graph.add_edge(
+                    giver_held,
+                    ItemPortalGraphNode::Held(entity, giver_held_index + 1),
+                    remaining_item_count,
+                );
+                item_portal_graph.add_edge(receiver_held, receiver_next_held, prior_item_count);
+            }
+            Move::Wait => {}
         }
     }
-    for &pos in plan.portals.iter() {
+    for pos in portals {
         let inventory = Inventory::Hypothetical(HypotheticalInventory::new());
         let player = out.ecs.insert_player(image_map, pos, inventory);
-        let portal = Portal::new(0, pos);
+        let portal = Portal::new(&mut out.ids, 0, pos);
         let portal_id = portal.id;
         out.portals.insert(portal)?;
         out.player_portal_graph.add_edge(
@@ -269,28 +346,226 @@ pub fn apply_plan(
         );
     }
 
+    // Patrols
+
+    let mut carried = Vec::new();
+    for (entity, patrol) in out.ecs.patrols.iter_mut() {
+        if !out.ecs.entities.contains_key(entity) {
+            continue;
+        }
+        let position = out
+            .ecs
+            .positions
+            .get_mut(entity)
+            .expect("Patrolling entity without position");
+        let start = *position;
+        let direction = if patrol.active(out.ecs.counters.get(entity)) {
+            patrol.step(position)
+        } else {
+            None
+        };
+        let teleported = *position != start;
+        out.ecs
+            .movement
+            .get_mut(entity)
+            .expect("Patrolling entity without movement")
+            .direction = direction;
+        if let Some(direction) = direction {
+            if let Some(guard) = out.ecs.guards.get_mut(entity) {
+                guard.facing = direction;
+            }
+            if out.ecs.carriers.contains_key(entity) && !teleported {
+                carried.push((start, direction));
+            }
+        }
+    }
+    // Players standing on a platform ride along, unless they're walking off it.
+    for (pt, direction) in carried {
+        if let Some(player) = player_at(&out.ecs, pt) {
+            let movement = out
+                .ecs
+                .movement
+                .get_mut(player)
+                .expect("Player without movement");
+            if movement.direction.is_none() {
+                movement.direction = Some(direction);
+            }
+        }
+    }
+
     // Movement
 
-    for (_entity, (position, movement)) in
-        inner_join(out.ecs.positions.iter_mut(), &out.ecs.movement)
-    {
+    // Only players are stopped by edge walls and one-way tiles. Moving walls, platforms and guards
+    // follow their paths regardless.
+    for (entity, (position, movement)) in inner_join(out.ecs.positions.iter(), &out.ecs.movement) {
+        if movement.movement_type != MovementType::PlayerControlled {
+            continue;
+        }
         if let Some(direction) = movement.direction {
-            let delta: nalgebra::Vector2<i32> = match direction {
-                Direction::Up => -nalgebra::Vector2::y(),
-                Direction::Down => nalgebra::Vector2::y(),
-                Direction::Left => -nalgebra::Vector2::x(),
-                Direction::Right => nalgebra::Vector2::x(),
+            if out.ecs.entities.contains_key(entity) && move_blocked(&out.ecs, *position, direction)
+            {
+                Err("Can't move that way")?;
+            }
+        }
+    }
+    // Only the moving entities' positions are written, so the rest of the table stays shared with
+    // the previous frame.
+    let moving: Vec<(Entity, Direction)> = out
+        .ecs
+        .movement
+        .iter()
+        .filter_map(|(entity, movement)| Some((entity, movement.direction?)))
+        .collect();
+    for (entity, direction) in moving {
+        if let Some(position) = out.ecs.positions.get_mut(entity) {
+            *position += direction.delta();
+        }
+    }
+    // Items that were already sitting on a conveyor get carried along, unless they'd run into
+    // something a player couldn't get past either.
+    let conveyed: Vec<(Id<ItemDrop>, Point, Direction)> = out
+        .items
+        .iter()
+        .filter(|(id, item_drop)| {
+            initial_frame
+                .items
+                .get_by_id(id)
+                .map_or(false, |prior| prior.position == item_drop.position)
+        })
+        .filter_map(|(&id, item_drop)| {
+            let direction = conveyor_at(&out.ecs, item_drop.position)?;
+            Some((id, item_drop.position, direction))
+        })
+        .collect();
+    for (id, position, direction) in conveyed {
+        let destination = position + direction.delta();
+        if !move_blocked(&out.ecs, position, direction) && !blocked_at(&out.ecs, destination) {
+            out.items
+                .update_by_id(&id, |item_drop| item_drop.position = destination)
+                .expect("Conveyed item went missing");
+        }
+    }
+    // Players on ice keep sliding, and players on conveyors get pushed, unless they'd run into
+    // something.
+    let forced: Vec<(Entity, Direction)> = out
+        .ecs
+        .players
+        .iter()
+        .map(|(player, _)| player)
+        .filter(|&player| out.ecs.entities.contains_key(player))
+        .filter_map(|player| {
+            let position = *out.ecs.positions.get(player)?;
+            let sliding = if on_ice(&out.ecs, position) {
+                out.ecs.movement.get(player)?.direction
+            } else {
+                None
+            };
+            let direction = sliding.or_else(|| conveyor_at(&out.ecs, position))?;
+            if move_blocked(&out.ecs, position, direction)
+                || blocked_at(&out.ecs, position + direction.delta())
+            {
+                None
+            } else {
+                Some((player, direction))
+            }
+        })
+        .collect();
+    let reset: Vec<(Entity, Option<Direction>)> = out
+        .ecs
+        .movement
+        .iter()
+        .filter_map(|(entity, movement)| {
+            let direction = match movement.movement_type {
+                MovementType::PlayerControlled => None,
+                MovementType::Constant(dir) => Some(dir),
+                MovementType::Patrol => movement.direction,
             };
-            *position += delta;
+            if direction == movement.direction {
+                None
+            } else {
+                Some((entity, direction))
+            }
+        })
+        .collect();
+    for (entity, direction) in reset {
+        out.ecs
+            .movement
+            .get_mut(entity)
+            .expect("Reset missing movement")
+            .direction = direction;
+    }
+    for (player, direction) in forced {
+        out.ecs
+            .movement
+            .get_mut(player)
+            .expect("Player without movement")
+            .direction = Some(direction);
+    }
+
+    // Teleporters
+
+    // Players who moved onto a teleporter this turn, and items dropped on one, get sent along.
+    let arrivals: Vec<(Entity, Point)> = out
+        .ecs
+        .players
+        .iter()
+        .map(|(player, _)| player)
+        .filter(|&player| out.ecs.entities.contains_key(player))
+        .filter_map(|player| {
+            let position = *out.ecs.positions.get(player)?;
+            let prior_position = *initial_frame.ecs.positions.get(player)?;
+            if position == prior_position {
+                None
+            } else {
+                Some((player, position))
+            }
+        })
+        .collect();
+    for (player, position) in arrivals {
+        let destination =
+            teleport_destination(&out.ecs, position, |pt| player_at(&out.ecs, pt).is_some());
+        if let Some(destination) = destination {
+            run_action(
+                &mut out,
+                player,
+                &Action::SetPosition {
+                    target: player,
+                    position: destination,
+                },
+            )?;
         }
     }
-    for (_entity, movement) in out.ecs.movement.iter_mut() {
-        match movement.movement_type {
-            MovementType::PlayerControlled => movement.direction = None,
-            MovementType::Constant(dir) => movement.direction = Some(dir),
+    let dropped: Vec<(Id<ItemDrop>, Point)> = out
+        .items
+        .iter()
+        .filter(|(id, _)| !initial_frame.items.contains_id(id))
+        .map(|(&id, item_drop)| (id, item_drop.position))
+        .collect();
+    for (id, position) in dropped {
+        // Items stack, so they're never blocked by other items.
+        if let Some(destination) = teleport_destination(&out.ecs, position, |_| false) {
+            out.items
+                .update_by_id(&id, |item_drop| item_drop.position = destination)
+                .expect("Dropped item went missing");
         }
     }
 
+    // Timers
+
+    // These run before the event listeners, so that listeners watching for rising and falling edges
+    // see their effects on the same turn.
+    for scheduled in out.scheduled.iter_mut() {
+        scheduled.turns -= 1;
+    }
+    let (due, pending): (Vec<_>, Vec<_>) = out
+        .scheduled
+        .drain(..)
+        .partition(|scheduled| scheduled.turns == 0);
+    out.scheduled = pending;
+    for scheduled in due {
+        run_action(&mut out, scheduled.entity, &scheduled.action)?;
+    }
+
     // Event Listeners
 
     // This is pretty stupid. We want to have out.ecs.event_listeners borrowed mutably while
@@ -298,12 +573,27 @@ pub fn apply_plan(
     // swap the event listeners into a separate variable, and swap it back when we're done.
     let mut event_listeners = Default::default();
     std::mem::swap(&mut event_listeners, &mut out.ecs.event_listeners);
+    // Listeners are found by entity and index rather than borrowed mutably, so only the ones whose
+    // modifiers change get copied out of the previous frame's table.
     let mut event_listeners_sorted = event_listeners
-        .iter_mut()
-        .flat_map(|(entity, listeners)| listeners.iter_mut().map(move |event| (entity, event)))
+        .iter()
+        .flat_map(|(entity, listeners)| {
+            listeners
+                .iter()
+                .enumerate()
+                .map(move |(ix, listener)| (entity, ix, listener.priority))
+        })
         .collect::<Vec<_>>();
-    event_listeners_sorted.sort_by_key(|(_, listener)| listener.priority);
-    for (entity, event_listener) in event_listeners_sorted {
+    event_listeners_sorted.sort_by_key(|&(_, _, priority)| priority);
+    // Gates sit between the main listeners, which feed them, and the cleanup listeners, which
+    // react to them.
+    let mut gates_evaluated = false;
+    for (entity, ix, priority) in event_listeners_sorted {
+        let event_listener = &event_listeners[entity][ix];
+        if !gates_evaluated && priority > Priority::Main {
+            evaluate_gates(&mut out.ecs);
+            gates_evaluated = true;
+        }
         let disabled = out
             .ecs
             .disabled_event_groups
@@ -334,115 +624,194 @@ pub fn apply_plan(
                 .and_then(|inventory| inventory.count_items().get(&item).copied())
                 .filter(|c| c >= required_count)
                 .is_some(),
-            EventTrigger::ItemIntersect(item) => out
+            EventTrigger::PlayerUse => out
                 .ecs
                 .positions
                 .get(entity)
-                .and_then(|pos| out.items.get_by_position(pos))
-                .filter(|drop| drop.item == *item)
-                .is_some(),
-            EventTrigger::CounterPredicate(counter, p) => {
-                let count = out
-                    .ecs
-                    .counters
-                    .get(entity)
-                    .map_or(0, |counters| counters[*counter]);
-                p(count)
+                .map_or(false, |pos| used_positions.contains(pos)),
+            EventTrigger::ItemIntersect(item) => {
+                out.ecs.positions.get(entity).map_or(false, |pos| {
+                    out.items
+                        .stack_at(pos)
+                        .iter()
+                        .any(|drop| drop.item == *item)
+                })
             }
+            EventTrigger::CounterPredicate(counter, p) => p(out.ecs.counter(entity, *counter)),
             EventTrigger::PositionPredicate(p) => {
                 out.ecs.positions.get(entity).map_or(false, |x| p(*x))
             }
         };
-        let triggered = match &mut event_listener.modifier {
-            EventTriggerModifier::Unmodified => base_triggered,
-            EventTriggerModifier::Negated => !base_triggered,
+        let (triggered, triggered_prior) = match event_listener.modifier {
+            EventTriggerModifier::Unmodified => (base_triggered, None),
+            EventTriggerModifier::Negated => (!base_triggered, None),
             EventTriggerModifier::Rising(triggered_prior) => {
-                let triggered = !*triggered_prior && base_triggered;
-                *triggered_prior = base_triggered;
-                triggered
+                (!triggered_prior && base_triggered, Some(triggered_prior))
             }
             EventTriggerModifier::Falling(triggered_prior) => {
-                let triggered = *triggered_prior && !base_triggered;
-                *triggered_prior = base_triggered;
-                triggered
+                (triggered_prior && !base_triggered, Some(triggered_prior))
             }
         };
+        if triggered_prior.map_or(false, |prior| prior != base_triggered) {
+            match event_listeners[entity][ix].modifier {
+                EventTriggerModifier::Rising(ref mut triggered_prior)
+                | EventTriggerModifier::Falling(ref mut triggered_prior) => {
+                    *triggered_prior = base_triggered
+                }
+                _ => panic!("Listener modifier changed kind"),
+            }
+        }
         if !triggered {
             continue;
         }
-        // We avoid explicit recursion to avoid the borrow checker thinking we might mutate
-        // ecs.event_listeners
-        let mut actions = vec![&event_listener.action];
-        while let Some(action) = actions.pop() {
-            match action {
-                Action::AlterCounter(target, counter, f) => {
-                    if !out.ecs.counters.contains_key(*target) {
-                        out.ecs.counters.insert(*target, EnumMap::new());
-                    }
-                    let counters = out
-                        .ecs
-                        .counters
-                        .get_mut(*target)
-                        .expect("Should have ensured that the counters existed");
-                    counters[*counter] = f(counters[*counter]);
-                }
-                Action::PlayerMarkUsed(item, count) => {
-                    let player_option = out
+        run_action(&mut out, entity, &event_listeners[entity][ix].action)?;
+    }
+    if !gates_evaluated {
+        evaluate_gates(&mut out.ecs);
+    }
+    std::mem::swap(&mut event_listeners, &mut out.ecs.event_listeners);
+
+    // Guards
+
+    for (guard, _) in out.ecs.guards.iter() {
+        if !out.ecs.entities.contains_key(guard) {
+            continue;
+        }
+        for pt in visible_tiles(&out.ecs, guard) {
+            if player_at(&out.ecs, pt).is_some() {
+                Err("Spotted by a guard")?;
+            }
+        }
+    }
+
+    // Jumpers
+
+    Ok(out)
+}
+
+// Runs `action` on behalf of `entity`: the owner of the listener or timer that triggered it.
+fn run_action(out: &mut GameFrame, entity: Entity, action: &Action) -> Result<(), GameError> {
+    // We avoid explicit recursion, so `All` can just push borrowed sub-actions onto a stack.
+    let mut actions = vec![action];
+    while let Some(action) = actions.pop() {
+        match action {
+            Action::AlterCounter(target, counter, f) => {
+                let count = out.ecs.counter_mut(*target, *counter);
+                *count = f(*count);
+            }
+            Action::PlayerMarkUsed(item, count) => {
+                let player_option = out
+                    .ecs
+                    .positions
+                    .get(entity)
+                    .and_then(|&pos| player_at(&out.ecs, pos));
+                if let Some(player) = player_option {
+                    let inventory = out
                         .ecs
-                        .positions
-                        .get(entity)
-                        .and_then(|&pos| player_at(&out.ecs, pos));
-                    if let Some(player) = player_option {
-                        let inventory = out
-                            .ecs
-                            .players
-                            .get_mut(player)
-                            .expect("Player has no inventory");
-                        let item_count = inventory.count_items().get(item).copied().unwrap_or(0);
-                        if item_count < *count {
-                            panic!("Not enough items for PlayerMarkUsed")
-                        }
-                        if let Inventory::Hypothetical(ref mut inventory) = inventory {
-                            let minimum = inventory.minima.entry(item.clone()).or_insert(0);
-                            *minimum = min(count - 1, *minimum);
-                        }
-                    };
-                }
-                Action::SetImage { target, img } => {
-                    out.ecs.images.insert(*target, *img);
+                        .players
+                        .get_mut(player)
+                        .expect("Player has no inventory");
+                    let item_count = inventory.count_items().get(item).copied().unwrap_or(0);
+                    if item_count < *count {
+                        panic!("Not enough items for PlayerMarkUsed")
+                    }
+                    if let Inventory::Hypothetical(ref mut inventory) = inventory {
+                        let minimum = inventory.minima.entry(item.clone()).or_insert(0);
+                        *minimum = min(count - 1, *minimum);
+                    }
+                };
+            }
+            Action::KillPlayer(cause) => {
+                let player_option = out
+                    .ecs
+                    .positions
+                    .get(entity)
+                    .and_then(|&pos| player_at(&out.ecs, pos));
+                if let Some(player) = player_option {
+                    out.kill_player(player, *cause);
                 }
-                Action::SetPosition { target, position } => {
-                    out.ecs.positions.insert(*target, *position);
+            }
+            Action::SetImage { target, img } => {
+                out.ecs.images.insert(*target, *img);
+            }
+            Action::SetPosition { target, position } => {
+                out.ecs.positions.insert(*target, *position);
+            }
+            Action::EnableGroup(target, group) => {
+                if let Some(disabled_groups) = out.ecs.disabled_event_groups.get_mut(*target) {
+                    disabled_groups.remove(*group);
                 }
-                Action::EnableGroup(target, group) => {
-                    if let Some(disabled_groups) = out.ecs.disabled_event_groups.get_mut(*target) {
-                        disabled_groups.remove(*group);
+            }
+            Action::DisableGroup(target, group) => {
+                match out.ecs.disabled_event_groups.get_mut(*target) {
+                    Some(disabled_groups) => {
+                        disabled_groups.insert(*group);
                     }
-                }
-                Action::DisableGroup(target, group) => {
-                    match out.ecs.disabled_event_groups.get_mut(*target) {
-                        Some(disabled_groups) => {
-                            disabled_groups.insert(*group);
-                        }
-                        None => {
-                            out.ecs
-                                .disabled_event_groups
-                                .insert(*target, EnumSet::only(*group));
-                        }
+                    None => {
+                        out.ecs
+                            .disabled_event_groups
+                            .insert(*target, EnumSet::only(*group));
                     }
                 }
-                Action::Reject(msg) => Err(*msg)?,
-                Action::All(new_actions) => {
-                    actions.extend(new_actions.iter());
-                }
+            }
+            Action::Delay(0, delayed) => actions.push(delayed),
+            Action::Delay(turns, delayed) => out.scheduled.push(ScheduledAction {
+                turns: *turns,
+                entity,
+                action: (**delayed).clone(),
+            }),
+            Action::Reject(msg) => Err(*msg)?,
+            Action::All(new_actions) => {
+                actions.extend(new_actions.iter());
             }
         }
     }
-    std::mem::swap(&mut event_listeners, &mut out.ecs.event_listeners);
+    Ok(())
+}
 
-    // Jumpers
+fn gate_inputs(ecs: &ECS, gate: &Gate) -> Vec<bool> {
+    gate.inputs
+        .iter()
+        .map(|&(input, counter)| ecs.counter(input, counter) > 0)
+        .collect()
+}
 
-    Ok(out)
+// Gates can feed into each other, so we keep re-evaluating them until nothing changes. Stateful
+// gates only update their state once everything has settled.
+fn evaluate_gates(ecs: &mut ECS) {
+    let gates: Vec<Entity> = ecs
+        .gates
+        .iter()
+        .map(|(gate, _)| gate)
+        .filter(|&gate| ecs.entities.contains_key(gate))
+        .collect();
+    for _ in 0..=gates.len() {
+        let mut changed = false;
+        for &gate in gates.iter() {
+            let output = {
+                let logic = ecs.gates.get(gate).expect("Gate went missing");
+                logic.output(&gate_inputs(ecs, logic)) as i64
+            };
+            let counter = ecs.counter_mut(gate, Counter::STATE);
+            if *counter != output {
+                *counter = output;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    for &gate in gates.iter() {
+        let inputs = gate_inputs(ecs, ecs.gates.get(gate).expect("Gate went missing"));
+        ecs.gates
+            .get_mut(gate)
+            .expect("Gate went missing")
+            .commit(&inputs);
+    }
 }
+
+#[cfg(test)]
+mod bench;
 #[cfg(test)]
 mod test;
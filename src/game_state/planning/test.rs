@@ -1,14 +1,22 @@
 use super::apply_plan;
 use crate::{
     game_frame::GameFrame,
-    types::{player_at, Direction, Entity, ImageMap, Move, Plan, Point},
+    game_state::history::{replay_subtree, HistoryNode},
+    portal_graph::ItemPortalGraphNode,
+    tree::RoseTree,
+    types::{
+        player_at, Action, Counter, Direction, Entity, GateKind, Group, ImageMap, Item, ItemDrop,
+        Key, MapElement, Move, Patrol, PatrolMode, Plan, Point,
+    },
 };
 use ggez::nalgebra::Point2;
 use proptest::{self, prelude::*};
 use std::collections::HashSet;
 
-static POSSIBLE_MOVES: [Move; 5] = [
+static POSSIBLE_MOVES: [Move; 7] = [
     Move::Jump,
+    Move::Use,
+    Move::Wait,
     Move::Direction(Direction::Up),
     Move::Direction(Direction::Down),
     Move::Direction(Direction::Left),
@@ -91,6 +99,51 @@ proptest! {
         }
     }
 }
+fn frame_and_plan() -> BoxedStrategy<(GameFrame, Plan)> {
+    unfold_arbitrary_plans(5)
+        .prop_flat_map(|frames| {
+            let frame = frames.last().expect("Empty frames vec").clone();
+            valid_plan(frame.clone()).prop_map(move |plan| (frame.clone(), plan))
+        })
+        .boxed()
+}
+
+proptest! {
+    #[test]
+    fn test_apply_plan_is_deterministic((ref frame, ref plan) in frame_and_plan()) {
+        let image_map = ImageMap::mock();
+        // An equal plan built separately iterates its moves and portals in a different order.
+        let rebuilt = Plan {
+            moves: plan.moves.iter().map(|(&entity, mv)| (entity, mv.clone())).collect(),
+            portals: plan.portals.iter().copied().collect(),
+        };
+        match (apply_plan(&image_map, frame, plan), apply_plan(&image_map, frame, &rebuilt)) {
+            (Ok(first), Ok(second)) => prop_assert_eq!(format!("{:?}", first), format!("{:?}", second)),
+            (Err(first), Err(second)) => prop_assert_eq!(first, second),
+            _ => prop_assert!(false, "Only one of the applications failed"),
+        }
+    }
+}
+// A turn where nothing moves leaves the component tables shared with the frame before it.
+#[test]
+fn test_unchanged_components_are_shared() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    for x in 0..20 {
+        MapElement::Wall.add(&image_map, Point2::new(x, 1), &mut game_frame_0.ecs);
+    }
+    // Inserted last, so the player's slot makes every table it's in as long as the walls'.
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    let mut plan = Plan::new();
+    plan.moves.insert(player_id, Move::Wait);
+    let game_frame_1 = apply_plan(&image_map, &game_frame_0, &plan).expect("Couldn't wait");
+    let (ecs_0, ecs_1) = (&game_frame_0.ecs, &game_frame_1.ecs);
+    assert!(ecs_1.positions.ptr_eq(&ecs_0.positions));
+    assert!(ecs_1.movement.ptr_eq(&ecs_0.movement));
+    assert!(ecs_1.event_listeners.ptr_eq(&ecs_0.event_listeners));
+}
 #[test]
 fn test_loop() {
     let image_map = ImageMap::mock();
@@ -141,3 +194,474 @@ fn test_two_jumps() {
     plan_3.moves.insert(player_0_id, Move::Jump);
     apply_plan(&image_map, &game_frame_3, &plan_3).expect("Couldn't perform second jump.");
 }
+#[test]
+fn test_spikes_kill_player() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    MapElement::Spikes.add(&image_map, Point2::new(1, 0), &mut game_frame_0.ecs);
+    let mut plan_0 = Plan::new();
+    plan_0
+        .moves
+        .insert(player_id, Move::Direction(Direction::Right));
+    let game_frame_1 =
+        apply_plan(&image_map, &game_frame_0, &plan_0).expect("Couldn't walk onto spikes");
+    assert!(!game_frame_1.ecs.entities.contains_key(player_id));
+    assert!(game_frame_1.failure().is_some());
+    apply_plan(&image_map, &game_frame_1, &Plan::new()).expect_err("Continued after dying");
+}
+#[test]
+fn test_guard_sight() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(4, 0))
+        .expect("Error insterting player");
+    MapElement::Guard {
+        path: Patrol::new(vec![Point2::new(0, 0)], PatrolMode::Loop),
+        facing: Direction::Right,
+        range: 3,
+    }
+    .add(&image_map, Point2::new(0, 0), &mut game_frame_0.ecs);
+    MapElement::Wall.add(&image_map, Point2::new(2, 0), &mut game_frame_0.ecs);
+    let mut plan_0 = Plan::new();
+    plan_0
+        .moves
+        .insert(player_id, Move::Direction(Direction::Left));
+    let game_frame_1 = apply_plan(&image_map, &game_frame_0, &plan_0).expect("Seen through a wall");
+    let mut plan_1 = Plan::new();
+    plan_1
+        .moves
+        .insert(player_id, Move::Direction(Direction::Down));
+    apply_plan(&image_map, &game_frame_1, &plan_1).expect_err("Guard didn't see player");
+}
+#[test]
+fn test_platform_carries_player() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    MapElement::Platform(Patrol::new(
+        vec![Point2::new(0, 0), Point2::new(2, 0)],
+        PatrolMode::PingPong,
+    ))
+    .add(&image_map, Point2::new(0, 0), &mut game_frame_0.ecs);
+    let mut game_frame = game_frame_0;
+    for &x in &[1, 2, 1] {
+        game_frame = apply_plan(&image_map, &game_frame, &Plan::new()).expect("Couldn't ride");
+        assert_eq!(
+            game_frame.ecs.positions.get(player_id),
+            Some(&Point2::new(x, 0))
+        );
+    }
+}
+#[test]
+fn test_reset_patrol() {
+    let image_map = ImageMap::mock();
+    let mut game_frame = GameFrame::new();
+    let wall = MapElement::MovingWall {
+        path: Patrol::new(
+            vec![Point2::new(0, 0), Point2::new(0, 2)],
+            PatrolMode::Reset,
+        )
+        .heading_to(1),
+        crushing: true,
+    }
+    .add(&image_map, Point2::new(0, 0), &mut game_frame.ecs);
+    // The wall rests on both ends: the reset back to the start takes a turn of its own.
+    for &y in &[1, 2, 0, 1] {
+        game_frame = apply_plan(&image_map, &game_frame, &Plan::new()).expect("Wall got stuck");
+        assert_eq!(game_frame.ecs.positions.get(wall), Some(&Point2::new(0, y)));
+    }
+}
+#[test]
+fn test_lever_toggles_door() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    let door = MapElement::RemoteDoor.add(&image_map, Point2::new(1, 0), &mut game_frame_0.ecs);
+    MapElement::Lever {
+        on: Action::DisableGroup(door, Group::Locked),
+        off: Action::EnableGroup(door, Group::Locked),
+    }
+    .add(&image_map, Point2::new(0, 0), &mut game_frame_0.ecs);
+    let mut use_plan = Plan::new();
+    use_plan.moves.insert(player_id, Move::Use);
+    let mut right_plan = Plan::new();
+    right_plan
+        .moves
+        .insert(player_id, Move::Direction(Direction::Right));
+    apply_plan(&image_map, &game_frame_0, &right_plan).expect_err("Door started unlocked");
+    let game_frame_1 =
+        apply_plan(&image_map, &game_frame_0, &use_plan).expect("Couldn't pull lever");
+    apply_plan(&image_map, &game_frame_1, &right_plan).expect("Lever didn't unlock door");
+    let game_frame_2 =
+        apply_plan(&image_map, &game_frame_1, &use_plan).expect("Couldn't pull lever back");
+    apply_plan(&image_map, &game_frame_2, &right_plan).expect_err("Lever didn't relock door");
+}
+#[test]
+fn test_and_gate() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    let switch_a = MapElement::Switch(Action::All(vec![])).add(
+        &image_map,
+        Point2::new(0, 0),
+        &mut game_frame_0.ecs,
+    );
+    let switch_b = MapElement::Switch(Action::All(vec![])).add(
+        &image_map,
+        Point2::new(1, 0),
+        &mut game_frame_0.ecs,
+    );
+    let door = MapElement::RemoteDoor.add(&image_map, Point2::new(2, 0), &mut game_frame_0.ecs);
+    MapElement::Gate {
+        kind: GateKind::And,
+        inputs: vec![(switch_a, Counter::STATE), (switch_b, Counter::STATE)],
+        rising: Action::DisableGroup(door, Group::Locked),
+        falling: Action::EnableGroup(door, Group::Locked),
+    }
+    .add(&image_map, Point2::new(9, 9), &mut game_frame_0.ecs);
+    let mut use_plan = Plan::new();
+    use_plan.moves.insert(player_id, Move::Use);
+    let mut right_plan = Plan::new();
+    right_plan
+        .moves
+        .insert(player_id, Move::Direction(Direction::Right));
+    let game_frame_1 =
+        apply_plan(&image_map, &game_frame_0, &use_plan).expect("Couldn't use first switch");
+    let game_frame_2 =
+        apply_plan(&image_map, &game_frame_1, &right_plan).expect("Couldn't walk right");
+    apply_plan(&image_map, &game_frame_2, &right_plan).expect_err("Door opened with one input");
+    let game_frame_3 =
+        apply_plan(&image_map, &game_frame_2, &use_plan).expect("Couldn't use second switch");
+    apply_plan(&image_map, &game_frame_3, &right_plan).expect("Door didn't open with both inputs");
+}
+#[test]
+fn test_timed_door() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    let door = MapElement::RemoteDoor.add(&image_map, Point2::new(1, 0), &mut game_frame_0.ecs);
+    MapElement::Switch(Action::All(vec![
+        Action::DisableGroup(door, Group::Locked),
+        Action::Delay(2, Box::new(Action::EnableGroup(door, Group::Locked))),
+    ]))
+    .add(&image_map, Point2::new(0, 0), &mut game_frame_0.ecs);
+    let mut use_plan = Plan::new();
+    use_plan.moves.insert(player_id, Move::Use);
+    let mut right_plan = Plan::new();
+    right_plan
+        .moves
+        .insert(player_id, Move::Direction(Direction::Right));
+    let game_frame_1 =
+        apply_plan(&image_map, &game_frame_0, &use_plan).expect("Couldn't use switch");
+    apply_plan(&image_map, &game_frame_1, &right_plan).expect("Door didn't open");
+    let game_frame_2 = apply_plan(&image_map, &game_frame_1, &Plan::new()).expect("Couldn't wait");
+    apply_plan(&image_map, &game_frame_2, &right_plan).expect_err("Door didn't close on time");
+}
+#[test]
+fn test_teleporter_pair() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    MapElement::Teleporter(Point2::new(5, 0)).add(
+        &image_map,
+        Point2::new(1, 0),
+        &mut game_frame_0.ecs,
+    );
+    MapElement::Teleporter(Point2::new(1, 0)).add(
+        &image_map,
+        Point2::new(5, 0),
+        &mut game_frame_0.ecs,
+    );
+    let mut plan_0 = Plan::new();
+    plan_0
+        .moves
+        .insert(player_id, Move::Direction(Direction::Right));
+    let game_frame_1 =
+        apply_plan(&image_map, &game_frame_0, &plan_0).expect("Couldn't walk onto teleporter");
+    assert_eq!(game_frame_1.ecs.positions[player_id], Point2::new(5, 0));
+    let game_frame_2 = apply_plan(&image_map, &game_frame_1, &Plan::new()).expect("Couldn't wait");
+    assert_eq!(game_frame_2.ecs.positions[player_id], Point2::new(5, 0));
+
+    // A player standing on the far teleporter blocks it.
+    let blocker_id = game_frame_0
+        .insert_player(&image_map, Point2::new(5, 0))
+        .expect("Error insterting player");
+    let game_frame_1 =
+        apply_plan(&image_map, &game_frame_0, &plan_0).expect("Couldn't walk onto teleporter");
+    assert_eq!(game_frame_1.ecs.positions[player_id], Point2::new(1, 0));
+    assert_eq!(game_frame_1.ecs.positions[blocker_id], Point2::new(5, 0));
+}
+#[test]
+fn test_ice_slides_until_wall() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    for x in 1..4 {
+        MapElement::Ice.add(&image_map, Point2::new(x, 0), &mut game_frame_0.ecs);
+    }
+    MapElement::Wall.add(&image_map, Point2::new(4, 0), &mut game_frame_0.ecs);
+    let mut plan_0 = Plan::new();
+    plan_0
+        .moves
+        .insert(player_id, Move::Direction(Direction::Right));
+    let mut game_frame =
+        apply_plan(&image_map, &game_frame_0, &plan_0).expect("Couldn't walk onto ice");
+    let mut steer = Plan::new();
+    steer
+        .moves
+        .insert(player_id, Move::Direction(Direction::Down));
+    apply_plan(&image_map, &game_frame, &steer).expect_err("Steered while sliding");
+    for &x in [2, 3, 3].iter() {
+        game_frame = apply_plan(&image_map, &game_frame, &Plan::new()).expect("Slid into a wall");
+        assert_eq!(game_frame.ecs.positions[player_id], Point2::new(x, 0));
+    }
+    apply_plan(&image_map, &game_frame, &steer).expect("Couldn't walk off stopped ice");
+}
+#[test]
+fn test_conveyor_pushes_player() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    MapElement::Conveyor(Direction::Down).add(&image_map, Point2::new(1, 0), &mut game_frame_0.ecs);
+    let mut plan_0 = Plan::new();
+    plan_0
+        .moves
+        .insert(player_id, Move::Direction(Direction::Right));
+    let game_frame_1 =
+        apply_plan(&image_map, &game_frame_0, &plan_0).expect("Couldn't walk onto conveyor");
+    assert_eq!(game_frame_1.ecs.positions[player_id], Point2::new(1, 0));
+    let game_frame_2 = apply_plan(&image_map, &game_frame_1, &Plan::new()).expect("Not pushed");
+    assert_eq!(game_frame_2.ecs.positions[player_id], Point2::new(1, 1));
+    let game_frame_3 = apply_plan(&image_map, &game_frame_2, &Plan::new()).expect("Couldn't wait");
+    assert_eq!(game_frame_3.ecs.positions[player_id], Point2::new(1, 1));
+}
+#[test]
+#[should_panic(expected = "never declared")]
+fn test_patrol_trigger_must_be_declared() {
+    let image_map = ImageMap::mock();
+    let mut game_frame = GameFrame::new();
+    // Declared somewhere else, so a typo as far as this frame is concerned.
+    let typo = GameFrame::new().ecs.declare_counter("unlcok");
+    MapElement::Platform(Patrol::new(vec![Point2::new(0, 0)], PatrolMode::Loop).with_trigger(typo))
+        .add(&image_map, Point2::new(0, 0), &mut game_frame.ecs);
+}
+#[test]
+fn test_edge_wall_stops_conveyed_item() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    MapElement::Conveyor(Direction::Right).add(
+        &image_map,
+        Point2::new(0, 0),
+        &mut game_frame_0.ecs,
+    );
+    MapElement::EdgeWall(Direction::Left).add(&image_map, Point2::new(1, 0), &mut game_frame_0.ecs);
+    MapElement::Conveyor(Direction::Right).add(
+        &image_map,
+        Point2::new(0, 1),
+        &mut game_frame_0.ecs,
+    );
+    for &y in &[0, 1] {
+        let item_drop = ItemDrop::new(&mut game_frame_0.ids, Item::Key(Key {}), Point2::new(0, y));
+        game_frame_0
+            .insert_item_drop(item_drop, 1)
+            .expect("Error inserting item");
+    }
+    let game_frame_1 = apply_plan(&image_map, &game_frame_0, &Plan::new()).expect("Couldn't wait");
+    let mut positions: Vec<Point> = game_frame_1
+        .items
+        .iter()
+        .map(|(_, item_drop)| item_drop.position)
+        .collect();
+    positions.sort_by_key(|pt| (pt.y, pt.x));
+    assert_eq!(positions, vec![Point2::new(0, 0), Point2::new(1, 1)]);
+}
+#[test]
+fn test_edge_wall_and_one_way() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    MapElement::EdgeWall(Direction::Left).add(&image_map, Point2::new(1, 0), &mut game_frame_0.ecs);
+    MapElement::OneWay(Direction::Down).add(&image_map, Point2::new(0, 1), &mut game_frame_0.ecs);
+    let plan = |direction| {
+        let mut plan = Plan::new();
+        plan.moves.insert(player_id, Move::Direction(direction));
+        plan
+    };
+    apply_plan(&image_map, &game_frame_0, &plan(Direction::Right))
+        .expect_err("Walked through an edge wall");
+    let game_frame_1 = apply_plan(&image_map, &game_frame_0, &plan(Direction::Down))
+        .expect("Couldn't enter one-way tile");
+    apply_plan(&image_map, &game_frame_1, &plan(Direction::Up))
+        .expect_err("Left one-way tile the wrong way");
+    apply_plan(&image_map, &game_frame_1, &plan(Direction::Down))
+        .expect("Couldn't leave one-way tile");
+}
+#[test]
+fn test_give_item() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let giver_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    let receiver_id = game_frame_0
+        .insert_player(&image_map, Point2::new(1, 0))
+        .expect("Error insterting player");
+    let far_id = game_frame_0
+        .insert_player(&image_map, Point2::new(3, 0))
+        .expect("Error insterting player");
+    let key = Item::Key(Key {});
+    let item_drop = ItemDrop::new(&mut game_frame_0.ids, key.clone(), Point2::new(0, 0));
+    game_frame_0
+        .insert_item_drop(item_drop, 1)
+        .expect("Error inserting item");
+    let mut plan_0 = Plan::new();
+    plan_0.moves.insert(giver_id, Move::PickUp(None));
+    let game_frame_1 = apply_plan(&image_map, &game_frame_0, &plan_0).expect("Couldn't pick up");
+    let mut far_plan = Plan::new();
+    far_plan.moves.insert(giver_id, Move::Give(0, far_id));
+    apply_plan(&image_map, &game_frame_1, &far_plan).expect_err("Gave to a distant player");
+    let mut plan_1 = Plan::new();
+    plan_1.moves.insert(giver_id, Move::Give(0, receiver_id));
+    let game_frame_2 = apply_plan(&image_map, &game_frame_1, &plan_1).expect("Couldn't give");
+    let count =
+        |frame: &GameFrame, player| frame.ecs.players[player].count_items().get(&key).copied();
+    assert_eq!(count(&game_frame_2, giver_id), None);
+    assert_eq!(count(&game_frame_2, receiver_id), Some(1));
+    assert!(game_frame_2.item_portal_graphs[&key].contains_edge(
+        ItemPortalGraphNode::Held(giver_id, 1),
+        ItemPortalGraphNode::Held(receiver_id, 1)
+    ));
+}
+#[test]
+fn test_throw_item() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    let key = Item::Key(Key {});
+    let item_drop = ItemDrop::new(&mut game_frame_0.ids, key.clone(), Point2::new(0, 0));
+    game_frame_0
+        .insert_item_drop(item_drop, 1)
+        .expect("Error inserting item");
+    MapElement::Pit.add(&image_map, Point2::new(1, 0), &mut game_frame_0.ecs);
+    MapElement::Wall.add(&image_map, Point2::new(3, 0), &mut game_frame_0.ecs);
+    let mut plan_0 = Plan::new();
+    plan_0.moves.insert(player_id, Move::PickUp(None));
+    let game_frame_1 = apply_plan(&image_map, &game_frame_0, &plan_0).expect("Couldn't pick up");
+    let mut plan_1 = Plan::new();
+    plan_1
+        .moves
+        .insert(player_id, Move::Throw(0, Direction::Right));
+    let game_frame_2 = apply_plan(&image_map, &game_frame_1, &plan_1).expect("Couldn't throw");
+    let item_drop = game_frame_2
+        .items
+        .get_by_position(&Point2::new(2, 0))
+        .expect("Thrown item didn't land in front of the wall");
+    assert!(game_frame_2.item_portal_graphs[&key].contains_edge(
+        ItemPortalGraphNode::Held(player_id, 1),
+        ItemPortalGraphNode::Dropped(item_drop.id)
+    ));
+}
+#[test]
+fn test_stacked_item_drops() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    let key = Item::Key(Key {});
+    let bottom = ItemDrop::new(&mut game_frame_0.ids, key.clone(), Point2::new(0, 0));
+    let bottom_id = bottom.id;
+    let top = ItemDrop::new(&mut game_frame_0.ids, key.clone(), Point2::new(0, 0));
+    let top_id = top.id;
+    game_frame_0
+        .insert_item_drop(bottom, 1)
+        .expect("Error inserting item");
+    game_frame_0
+        .insert_item_drop(top, 1)
+        .expect("Couldn't stack items");
+    let mut plan_0 = Plan::new();
+    plan_0.moves.insert(player_id, Move::PickUp(Some(0)));
+    let game_frame_1 = apply_plan(&image_map, &game_frame_0, &plan_0).expect("Couldn't pick up");
+    assert!(!game_frame_1.items.contains_id(&bottom_id));
+    assert_eq!(game_frame_1.items.stack_at(&Point2::new(0, 0)).len(), 1);
+    // Dropping onto the remaining item stacks on top of it.
+    let mut plan_1 = Plan::new();
+    plan_1.moves.insert(player_id, Move::Drop(0));
+    let game_frame_2 = apply_plan(&image_map, &game_frame_1, &plan_1).expect("Couldn't drop");
+    let stack = game_frame_2.items.stack_at(&Point2::new(0, 0));
+    assert_eq!(stack.len(), 2);
+    assert_eq!(stack[0].id, top_id);
+}
+
+#[test]
+fn test_replay_subtree() {
+    let image_map = ImageMap::mock();
+    let mut game_frame_0 = GameFrame::new();
+    let player_id = game_frame_0
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error insterting player");
+    MapElement::Wall.add(&image_map, Point2::new(1, 2), &mut game_frame_0.ecs);
+    let plan = |direction| {
+        let mut plan = Plan::new();
+        plan.moves.insert(player_id, Move::Direction(direction));
+        plan
+    };
+    let game_frame_1 =
+        apply_plan(&image_map, &game_frame_0, &plan(Direction::Right)).expect("Couldn't move");
+    let game_frame_2 =
+        apply_plan(&image_map, &game_frame_1, &plan(Direction::Down)).expect("Couldn't move");
+    let subtree = RoseTree {
+        val: HistoryNode::new(game_frame_1),
+        children: vec![(
+            plan(Direction::Down),
+            RoseTree::singleton(HistoryNode::new(game_frame_2)),
+        )],
+    };
+    let rebased = replay_subtree(
+        &image_map,
+        &game_frame_0,
+        &plan(Direction::Down),
+        &subtree,
+        &mut vec![0],
+    )
+    .expect("Couldn't rebase");
+    assert_eq!(rebased.val.ecs.positions[player_id], Point2::new(0, 1));
+    assert_eq!(
+        rebased.children[0].1.val.ecs.positions[player_id],
+        Point2::new(0, 2)
+    );
+    // Starting one tile further right, the second turn walks into the wall.
+    let mut game_frame_0_right = game_frame_0.clone();
+    game_frame_0_right.ecs.positions[player_id] = Point2::new(1, 0);
+    let err = replay_subtree(
+        &image_map,
+        &game_frame_0_right,
+        &plan(Direction::Down),
+        &subtree,
+        &mut vec![0],
+    )
+    .expect_err("Walked into a wall");
+    assert!(err.contains("[0, 0]"));
+}
@@ -0,0 +1,476 @@
+use super::{history::hypothetical_cell, Edit, GameState, HistoryStorage};
+use crate::{
+    game_frame::GameFrame,
+    render,
+    types::{
+        player_at, CachablePlan, Direction, Entity, ImageMap, Item, Key, MapElement, Move, Plan,
+        Point,
+    },
+};
+use ggez::{graphics::Rect, nalgebra::Point2};
+use std::collections::{HashMap, VecDeque};
+
+// A game with one player at the origin, plus whatever `build` adds to the first frame.
+fn game_with_player<F: FnOnce(&ImageMap, &mut GameFrame)>(build: F) -> (GameState, Entity) {
+    let image_map = ImageMap::mock();
+    let mut frame = GameFrame::new();
+    let player = frame
+        .insert_player(&image_map, Point2::new(0, 0))
+        .expect("Error inserting player");
+    build(&image_map, &mut frame);
+    (GameState::from_frame(image_map, frame), player)
+}
+
+fn position(game_state: &GameState, entity: Entity) -> Option<Point> {
+    game_state
+        .history
+        .get_focus_val()
+        .ecs
+        .positions
+        .get(entity)
+        .copied()
+}
+
+const RIGHT: Move = Move::Direction(Direction::Right);
+const DOWN: Move = Move::Direction(Direction::Down);
+
+#[test]
+fn test_queues_feed_plans() {
+    let (mut game_state, player) = game_with_player(|_, _| {});
+    game_state
+        .edit(Edit::SetMove(player, RIGHT))
+        .expect("Couldn't plan");
+    game_state
+        .edit(Edit::QueueMove(player, RIGHT))
+        .expect("Couldn't queue");
+    game_state
+        .edit(Edit::QueueMove(player, DOWN))
+        .expect("Couldn't queue");
+    game_state.step().expect("Couldn't step");
+    // The front of the queue became the next plan.
+    let plan = game_state
+        .current_plan
+        .get(&game_state.history.focus.children);
+    assert_eq!(plan.moves.get(&player), Some(&RIGHT));
+    assert_eq!(game_state.queues[&player].len(), 1);
+    game_state.step().expect("Couldn't step");
+    assert!(game_state.queues.is_empty());
+    game_state.step().expect("Couldn't step");
+    assert_eq!(position(&game_state, player), Some(Point2::new(2, 1)));
+    assert_eq!(game_state.history.path(), vec![0, 0, 0]);
+}
+
+#[test]
+fn test_queues_of_dead_players_are_dropped() {
+    let (mut game_state, player) = game_with_player(|image_map, frame| {
+        MapElement::Spikes.add(image_map, Point2::new(1, 0), &mut frame.ecs);
+    });
+    game_state
+        .edit(Edit::SetMove(player, RIGHT))
+        .expect("Couldn't plan");
+    game_state
+        .edit(Edit::QueueMove(player, RIGHT))
+        .expect("Couldn't queue");
+    game_state.step().expect("Couldn't step");
+    assert!(game_state.history.get_focus_val().failure().is_some());
+    assert!(game_state.queues.is_empty());
+    assert!(game_state
+        .current_plan
+        .get(&game_state.history.focus.children)
+        .moves
+        .is_empty());
+}
+
+#[test]
+fn test_queues_of_merged_players_are_dropped() {
+    let (mut game_state, player_0) = game_with_player(|_, _| {});
+    game_state
+        .edit(Edit::TogglePortal(Point2::new(1, 0)))
+        .expect("Couldn't plan a portal");
+    game_state
+        .edit(Edit::TogglePortal(Point2::new(2, 0)))
+        .expect("Couldn't plan a portal");
+    game_state.step().expect("Couldn't open portals");
+    let ecs = &game_state.history.get_focus_val().ecs;
+    let player_1 = player_at(ecs, Point2::new(1, 0)).expect("No player at (1,0)");
+    let player_2 = player_at(ecs, Point2::new(2, 0)).expect("No player at (2,0)");
+    for &player in &[player_0, player_1, player_2] {
+        game_state
+            .edit(Edit::SetMove(player, RIGHT))
+            .expect("Couldn't plan");
+    }
+    game_state
+        .edit(Edit::QueueMove(player_1, Move::Jump))
+        .expect("Couldn't queue");
+    game_state
+        .edit(Edit::QueueMove(player_1, Move::Wait))
+        .expect("Couldn't queue");
+    game_state.step().expect("Couldn't move right");
+    game_state.step().expect("Couldn't jump");
+    let ecs = &game_state.history.get_focus_val().ecs;
+    assert!(!ecs.entities.contains_key(player_1));
+    assert!(game_state.queues.is_empty());
+}
+
+#[test]
+fn test_run_queues_stops_at_failing_turn() {
+    let (mut game_state, player) = game_with_player(|image_map, frame| {
+        MapElement::Wall.add(image_map, Point2::new(3, 0), &mut frame.ecs);
+    });
+    game_state
+        .edit(Edit::SetMove(player, RIGHT))
+        .expect("Couldn't plan");
+    for _ in 0..3 {
+        game_state
+            .edit(Edit::QueueMove(player, RIGHT))
+            .expect("Couldn't queue");
+    }
+    game_state.run_queues().expect_err("Walked through a wall");
+    assert_eq!(game_state.history.path(), vec![0, 0]);
+    assert_eq!(position(&game_state, player), Some(Point2::new(2, 0)));
+    // The turn that failed is still planned, along with the rest of the queue.
+    let plan = game_state
+        .current_plan
+        .get(&game_state.history.focus.children);
+    assert_eq!(plan.moves.get(&player), Some(&RIGHT));
+    assert_eq!(game_state.queues[&player].len(), 1);
+}
+
+#[test]
+fn test_repeated_runs_follow_recorded_plans() {
+    let (mut game_state, player) = game_with_player(|_, _| {});
+    for _ in 0..2 {
+        game_state.go_to(&[]).expect("Couldn't go to the root");
+        game_state
+            .edit(Edit::SetMove(player, RIGHT))
+            .expect("Couldn't plan");
+        game_state
+            .edit(Edit::QueueMove(player, DOWN))
+            .expect("Couldn't queue");
+        game_state.run_queues().expect("Couldn't run queues");
+        assert_eq!(game_state.history.path(), vec![0, 0]);
+    }
+    let tree = game_state.history.rezip();
+    assert_eq!(tree.children.len(), 1);
+    assert_eq!(tree.children[0].1.children.len(), 1);
+    match game_state.current_plan {
+        CachablePlan::Novel(ref plan) => assert!(plan.moves.is_empty()),
+        _ => panic!("Stepped past the end of the history"),
+    }
+}
+
+#[test]
+fn test_require_orders() {
+    let mut player_1 = None;
+    let (mut game_state, player_0) = game_with_player(|image_map, frame| {
+        player_1 = frame.insert_player(image_map, Point2::new(0, 2)).ok();
+    });
+    let player_1 = player_1.expect("Error inserting player");
+    game_state.require_orders = true;
+    game_state
+        .edit(Edit::SetMove(player_0, RIGHT))
+        .expect("Couldn't plan");
+    assert_eq!(game_state.unplanned_players(), vec![player_1]);
+    game_state
+        .step()
+        .expect_err("Stepped without orders for every player");
+    assert!(game_state.history.path().is_empty());
+    // Waiting is an order.
+    game_state
+        .edit(Edit::SetMove(player_1, Move::Wait))
+        .expect("Couldn't plan");
+    assert!(game_state.unplanned_players().is_empty());
+    game_state.step().expect("Couldn't step with full orders");
+    assert_eq!(position(&game_state, player_1), Some(Point2::new(0, 2)));
+}
+
+#[test]
+fn test_replay_storage_recomputes_frames() {
+    let (mut game_state, player) = game_with_player(|_, _| {});
+    for mv in [RIGHT, DOWN, RIGHT].iter() {
+        game_state
+            .edit(Edit::SetMove(player, mv.clone()))
+            .expect("Couldn't plan");
+        game_state.step().expect("Couldn't step");
+    }
+    game_state.go_to(&[0]).expect("Couldn't go back");
+    game_state
+        .edit(Edit::SetMove(player, RIGHT))
+        .expect("Couldn't plan");
+    game_state.step().expect("Couldn't branch");
+    let originals: Vec<(Vec<usize>, String)> = game_state
+        .history
+        .dfs()
+        .map(|node| (node.path, format!("{:?}", &**node.val)))
+        .collect();
+    game_state.storage = HistoryStorage::Replay { cache: 0 };
+    game_state.enforce_storage();
+    let evicted = game_state
+        .history
+        .dfs()
+        .filter(|node| node.val.is_evicted())
+        .count();
+    // Everything but the root and the focus.
+    assert_eq!(evicted, originals.len() - 2);
+    for (path, original) in originals {
+        game_state.go_to(&path).expect("Couldn't recompute a frame");
+        let frame: &GameFrame = game_state.history.get_focus_val();
+        assert_eq!(format!("{:?}", frame), original);
+    }
+}
+
+// The plan being written and the queues, which are what undoing non-wish edits restores.
+fn planning(game_state: &GameState) -> (Plan, HashMap<Entity, VecDeque<Move>>) {
+    let plan = game_state
+        .current_plan
+        .get(&game_state.history.focus.children);
+    (plan.clone(), game_state.queues.clone())
+}
+
+#[test]
+fn test_undo_redo_round_trip() {
+    let (mut game_state, player) = game_with_player(|_, _| {});
+    let edits = vec![
+        Edit::SetMove(player, RIGHT),
+        Edit::QueueMove(player, DOWN),
+        Edit::TogglePortal(Point2::new(1, 1)),
+    ];
+    let mut states = vec![planning(&game_state)];
+    for edit in edits {
+        game_state.edit(edit).expect("Couldn't edit");
+        states.push(planning(&game_state));
+    }
+    for state in states.iter().rev().skip(1) {
+        game_state.undo().expect("Couldn't undo");
+        assert_eq!(&planning(&game_state), state);
+    }
+    game_state
+        .undo()
+        .expect_err("Undid more edits than were made");
+    for state in states.iter().skip(1) {
+        game_state.redo().expect("Couldn't redo");
+        assert_eq!(&planning(&game_state), state);
+    }
+    game_state
+        .redo()
+        .expect_err("Redid more edits than were undone");
+}
+
+#[test]
+fn test_new_edit_clears_redo() {
+    let (mut game_state, player) = game_with_player(|_, _| {});
+    game_state
+        .edit(Edit::SetMove(player, RIGHT))
+        .expect("Couldn't plan");
+    game_state.undo().expect("Couldn't undo");
+    game_state
+        .edit(Edit::SetMove(player, DOWN))
+        .expect("Couldn't plan");
+    game_state
+        .redo()
+        .expect_err("Redid an edit that was overwritten");
+    let (plan, _) = planning(&game_state);
+    assert_eq!(plan.moves.get(&player), Some(&DOWN));
+}
+
+#[test]
+fn test_wishes_are_undone_and_replayed() {
+    let (mut game_state, player_0) = game_with_player(|_, _| {});
+    game_state
+        .edit(Edit::TogglePortal(Point2::new(1, 0)))
+        .expect("Couldn't plan a portal");
+    game_state.step().expect("Couldn't open a portal");
+    game_state.step().expect("Couldn't step");
+    game_state.go_to(&[0]).expect("Couldn't go back");
+    let player_1 = player_at(&game_state.history.get_focus_val().ecs, Point2::new(1, 0))
+        .expect("No player from the portal");
+    // The wished slot, at the focus and the turn after it.
+    let slots = |game_state: &GameState| {
+        let focus = &game_state.history.focus;
+        let slot = |frame: &GameFrame| {
+            hypothetical_cell(frame, player_1, 0)
+                .expect("Lost the hypothetical inventory")
+                .map(|cell| (cell.item.clone(), cell.count))
+        };
+        (slot(&focus.val), slot(&focus.children[0].1.val))
+    };
+    let key = Item::Key(Key {});
+    game_state
+        .edit(Edit::Wish(player_1, 99, key.clone()))
+        .expect_err("Wished into a slot that doesn't exist");
+    game_state
+        .edit(Edit::Unwish(player_1, 99))
+        .expect_err("Unwished from a slot that doesn't exist");
+    game_state
+        .edit(Edit::Wish(player_1, 0, key.clone()))
+        .expect("Couldn't wish");
+    let wished = Some((key, 1));
+    assert_eq!(slots(&game_state), (wished.clone(), wished.clone()));
+    game_state.undo().expect("Couldn't undo");
+    assert_eq!(slots(&game_state), (None, None));
+    assert!(game_state.history.focus.val.wishes().is_empty());
+    // Rebasing the turn before replays the wish along with the plans.
+    game_state.redo().expect("Couldn't redo");
+    game_state.go_to(&[]).expect("Couldn't go to the root");
+    game_state
+        .edit(Edit::SetMove(player_0, Move::Wait))
+        .expect("Couldn't plan");
+    game_state.rebase().expect("Couldn't rebase past a wish");
+    game_state
+        .go_to(&[0])
+        .expect("Couldn't go to the wished turn");
+    assert_eq!(game_state.history.focus.val.wishes().len(), 1);
+    assert_eq!(slots(&game_state), (wished.clone(), wished));
+}
+
+#[test]
+fn test_diff_across_branches() {
+    let (mut game_state, _) = game_with_player(|_, _| {});
+    game_state
+        .edit(Edit::TogglePortal(Point2::new(1, 0)))
+        .expect("Couldn't plan a portal");
+    game_state.step().expect("Couldn't open a portal");
+    game_state.go_to(&[]).expect("Couldn't go to the root");
+    game_state
+        .edit(Edit::TogglePortal(Point2::new(1, 0)))
+        .expect("Couldn't unplan a portal");
+    game_state
+        .edit(Edit::TogglePortal(Point2::new(2, 0)))
+        .expect("Couldn't plan a portal");
+    game_state.step().expect("Couldn't open a portal");
+    assert_eq!(game_state.history.path(), vec![1]);
+    let frame = GameFrame::clone(game_state.history.get_focus_val());
+    game_state.diff_base = Some((vec![1], frame));
+    game_state
+        .go_to(&[0])
+        .expect("Couldn't go to the other branch");
+    let diff = game_state.current_diff().expect("No diff");
+    // Each branch's player from its portal has the same key.
+    assert!(diff.moved.is_empty());
+    let positions = |changes: &[(Entity, Option<Point>)]| -> Vec<Option<Point>> {
+        changes.iter().map(|&(_, position)| position).collect()
+    };
+    assert_eq!(positions(&diff.removed), vec![Some(Point2::new(2, 0))]);
+    assert_eq!(positions(&diff.appeared), vec![Some(Point2::new(1, 0))]);
+}
+
+#[test]
+fn test_rebase_stops_at_failing_side_branch() {
+    let (mut game_state, player) = game_with_player(|image_map, frame| {
+        MapElement::Wall.add(image_map, Point2::new(0, 2), &mut frame.ecs);
+    });
+    game_state
+        .edit(Edit::SetMove(player, RIGHT))
+        .expect("Couldn't plan");
+    game_state.step().expect("Couldn't step");
+    game_state
+        .edit(Edit::SetMove(player, RIGHT))
+        .expect("Couldn't plan");
+    game_state.step().expect("Couldn't step");
+    game_state.go_to(&[0]).expect("Couldn't go back");
+    game_state
+        .edit(Edit::SetMove(player, DOWN))
+        .expect("Couldn't plan");
+    game_state.step().expect("Couldn't branch");
+    assert_eq!(game_state.history.path(), vec![0, 1]);
+    game_state.go_to(&[]).expect("Couldn't go to the root");
+    let frames = |game_state: &GameState| -> Vec<(Vec<usize>, String)> {
+        game_state
+            .history
+            .dfs()
+            .map(|node| (node.path, format!("{:?}", &**node.val)))
+            .collect()
+    };
+    let originals = frames(&game_state);
+    // Going down first still leaves room to go right, but the second branch now walks into the wall.
+    game_state
+        .edit(Edit::SetMove(player, DOWN))
+        .expect("Couldn't plan");
+    let err = game_state.rebase().expect_err("Rebased onto a wall");
+    assert!(
+        err.contains("[0, 1]"),
+        "Error doesn't name the turn: {}",
+        err
+    );
+    assert_eq!(frames(&game_state), originals);
+    // The edit is still there to step with or change.
+    match game_state.current_plan {
+        CachablePlan::Edited(0, ref plan) => assert_eq!(plan.moves.get(&player), Some(&DOWN)),
+        _ => panic!("Lost the edited plan"),
+    }
+}
+
+#[test]
+fn test_bookmarks_follow_deletes_and_prunes() {
+    let (mut game_state, player) = game_with_player(|_, _| {});
+    // Three turns from the root, and one more after the last.
+    for mv in [RIGHT, DOWN, Move::Wait].iter() {
+        game_state.go_to(&[]).expect("Couldn't go to the root");
+        game_state
+            .edit(Edit::SetMove(player, mv.clone()))
+            .expect("Couldn't plan");
+        game_state.step().expect("Couldn't step");
+    }
+    game_state
+        .edit(Edit::SetMove(player, RIGHT))
+        .expect("Couldn't plan");
+    game_state.step().expect("Couldn't step");
+    assert_eq!(game_state.history.path(), vec![2, 0]);
+    let mut frames = HashMap::new();
+    for (name, path) in &[
+        ("right", vec![0]),
+        ("down", vec![1]),
+        ("wait", vec![2]),
+        ("then", vec![2, 0]),
+    ] {
+        game_state.go_to(path).expect("Couldn't go to a turn");
+        game_state.add_bookmark(name.to_string(), String::new());
+        frames.insert(
+            name.to_string(),
+            format!("{:?}", &**game_state.history.get_focus_val()),
+        );
+    }
+    let check_bookmarks = |game_state: &mut GameState, expected: &[(&str, Vec<usize>)]| {
+        let bookmarks: Vec<(String, Vec<usize>)> = game_state
+            .bookmarks
+            .iter()
+            .map(|bookmark| (bookmark.name.clone(), bookmark.path.clone()))
+            .collect();
+        let expected: Vec<(String, Vec<usize>)> = expected
+            .iter()
+            .map(|(name, path)| (name.to_string(), path.clone()))
+            .collect();
+        assert_eq!(bookmarks, expected);
+        for (name, path) in bookmarks {
+            game_state.go_to(&path).expect("Bookmark doesn't resolve");
+            assert_eq!(
+                format!("{:?}", &**game_state.history.get_focus_val()),
+                frames[&name],
+                "Bookmark {} moved to another turn",
+                name
+            );
+        }
+    };
+    game_state
+        .go_to(&[1])
+        .expect("Couldn't go to the middle turn");
+    game_state.delete_branch().expect("Couldn't delete");
+    check_bookmarks(
+        &mut game_state,
+        &[("right", vec![0]), ("wait", vec![1]), ("then", vec![1, 0])],
+    );
+    game_state
+        .go_to(&[1])
+        .expect("Couldn't go to the last turn");
+    game_state.keep_only_path();
+    check_bookmarks(&mut game_state, &[("wait", vec![0]), ("then", vec![0, 0])]);
+    // Clicking each node in the history panel goes to it.
+    let bounds = Rect::new(0., 0., 300., 600.);
+    for (row, node) in game_state.history.dfs().enumerate() {
+        let pt = render::history_node_position(bounds, row, node.path.len());
+        assert_eq!(
+            render::history_panel_node_at(bounds, &game_state.history, pt),
+            Some(node.path)
+        );
+    }
+}
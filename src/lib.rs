@@ -1,4 +1,5 @@
 #![feature(nll)]
+#![cfg_attr(test, feature(test))]
 #![warn(clippy::all)]
 #![allow(clippy::try_err)]
 
@@ -6,19 +7,20 @@
 #[macro_use]
 pub extern crate proptest;
 extern crate ggez;
+extern crate im_rc;
 extern crate petgraph;
-extern crate rand;
+#[cfg(test)]
+extern crate test;
+#[cfg(test)]
 #[macro_use]
 extern crate slotmap;
 #[macro_use]
 extern crate derivative;
 extern crate objekt;
 #[macro_use]
-extern crate enum_map;
-#[macro_use]
 extern crate enumset;
 
-mod game_frame;
+pub mod game_frame;
 pub mod game_state;
 mod portal_graph;
 mod render;
@@ -1,8 +1,9 @@
 extern crate time_game_lib;
 
 use time_game_lib::{
+    game_frame::GameFrame,
     game_state::GameState,
-    types::{Action, Counter, Direction, Group, Item, ItemDrop, Key, MapElement},
+    types::{Action, Group, ImageMap, Item, ItemDrop, Key, MapElement, Patrol, PatrolMode},
 };
 
 extern crate ggez;
@@ -29,41 +30,36 @@ pub fn main() {
     }
 
     let (ctx, event_loop) = &mut cb.build().unwrap();
-    let game_state = &mut GameState::new(ctx).unwrap();
-    let game_frame = game_state.history.get_focus_val_mut();
+    let image_map = ImageMap::new(ctx).unwrap();
+    let mut game_frame = GameFrame::new();
     game_frame
-        .insert_player(&game_state.image_map, Point2::new(0, 4))
+        .insert_player(&image_map, Point2::new(0, 4))
         .expect("Could not insert player");
+    let key = ItemDrop::new(&mut game_frame.ids, Item::Key(Key {}), Point2::new(1, 1));
     game_frame
-        .insert_item_drop(ItemDrop::new(Item::Key(Key {}), Point2::new(1, 1)), 1)
+        .insert_item_drop(key, 1)
         .expect("Could not insert item");
-    let light_door = MapElement::RemoteDoor.add(
-        &game_state.image_map,
-        Point2::new(5, 6),
-        &mut game_frame.ecs,
-    );
+    let light_door = MapElement::RemoteDoor.add(&image_map, Point2::new(5, 6), &mut game_frame.ecs);
+    let unlock = game_frame.ecs.declare_counter("unlock");
     let light = MapElement::Light {
-        counter: Counter::Unlock,
+        counter: unlock,
+        threshold: 3,
         rising: Action::All(vec![
             Action::SetImage {
                 target: light_door,
-                img: game_state.image_map.open_door,
+                img: image_map.open_door,
             },
             Action::DisableGroup(light_door, Group::Locked),
         ]),
         falling: Action::All(vec![
             Action::SetImage {
                 target: light_door,
-                img: game_state.image_map.closed_door,
+                img: image_map.closed_door,
             },
             Action::EnableGroup(light_door, Group::Locked),
         ]),
     }
-    .add(
-        &game_state.image_map,
-        Point2::new(5, 5),
-        &mut game_frame.ecs,
-    );
+    .add(&image_map, Point2::new(5, 5), &mut game_frame.ecs);
     let map = [
         (
             MapElement::Wall,
@@ -103,25 +99,26 @@ pub fn main() {
         ),
         (MapElement::ClosedDoor, vec![(1, 3)]),
         (
-            MapElement::Plate(Counter::Unlock, light),
+            MapElement::Plate(unlock, light),
             vec![(2, 5), (3, 5), (4, 5)],
         ),
         (
             MapElement::MovingWall {
-                reset: Some((Point2::new(8, 0), Point2::new(8, 12))),
-                direction: Direction::Down,
+                path: Patrol::new(
+                    vec![Point2::new(8, 0), Point2::new(8, 11)],
+                    PatrolMode::Reset,
+                )
+                .heading_to(1),
+                crushing: true,
             },
             vec![(8, 0), (8, 4), (8, 8)],
         ),
     ];
     for (el, pts) in map.iter() {
         for &(x, y) in pts {
-            el.add(
-                &game_state.image_map,
-                Point2::new(x, y),
-                &mut game_frame.ecs,
-            );
+            el.add(&image_map, Point2::new(x, y), &mut game_frame.ecs);
         }
     }
+    let game_state = &mut GameState::from_frame(image_map, game_frame);
     event::run(ctx, event_loop, game_state).unwrap();
 }
@@ -7,6 +7,7 @@ pub enum PlayerPortalGraphNode {
     Beginning,
     Portal(Id<Portal>),
     End,
+    Death(Entity), //Where a killed player's worldline stops, instead of End.
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
@@ -8,6 +8,12 @@ use self::nalgebra::{Similarity2, Vector2};
 use super::ggez::nalgebra;
 
 use super::types::*;
+use crate::{
+    game_frame::diff::FrameDiff,
+    game_state::history::HistoryNode,
+    tree::{Path, Zipper},
+};
+use std::collections::HashMap;
 
 type Point2 = ggez::nalgebra::Point2<f32>;
 
@@ -139,3 +145,245 @@ pub fn ecs(ctx: &mut ggez::Context, ecs: &ECS) -> ggez::GameResult<()> {
     }
     Ok(())
 }
+
+pub fn guard_vision(ctx: &mut ggez::Context, ecs: &ECS) -> ggez::GameResult<()> {
+    let bounds = graphics::screen_coordinates(ctx);
+    let color = graphics::Color::new(1., 0.8, 0., 0.3);
+    for (guard, _) in ecs.guards.iter() {
+        if !ecs.entities.contains_key(guard) {
+            continue;
+        }
+        for pt in visible_tiles(ecs, guard) {
+            let pixel_space_pt = tile_space_to_pixel_space(pt, bounds);
+            Mesh::new_rectangle(
+                ctx,
+                graphics::DrawMode::Fill(Default::default()),
+                graphics::Rect::new(pixel_space_pt.x, pixel_space_pt.y, SCALE, SCALE),
+                color,
+            )?
+            .draw(ctx, DrawParam::new())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn edge_walls(ctx: &mut ggez::Context, ecs: &ECS) -> ggez::GameResult<()> {
+    let bounds = graphics::screen_coordinates(ctx);
+    for (entity, side) in ecs.edge_walls.iter() {
+        if !ecs.entities.contains_key(entity) {
+            continue;
+        }
+        let pt = match ecs.positions.get(entity) {
+            Some(pt) => *pt,
+            None => continue,
+        };
+        let top_left = tile_space_to_pixel_space(pt, bounds);
+        let (x0, y0, x1, y1) = (
+            top_left.x,
+            top_left.y,
+            top_left.x + SCALE,
+            top_left.y + SCALE,
+        );
+        let line = match side {
+            Direction::Up => [[x0, y0], [x1, y0]],
+            Direction::Down => [[x0, y1], [x1, y1]],
+            Direction::Left => [[x0, y0], [x0, y1]],
+            Direction::Right => [[x1, y0], [x1, y1]],
+        };
+        Mesh::new_line(ctx, &line, 10., graphics::BLACK)?.draw(ctx, DrawParam::default())?;
+    }
+    Ok(())
+}
+
+const HISTORY_PANEL_WIDTH: f32 = 300.;
+const HISTORY_ROW_HEIGHT: f32 = 20.;
+const HISTORY_INDENT: f32 = 15.;
+
+pub fn history_panel_bbox(ctx: &ggez::Context) -> graphics::Rect {
+    let screen_bounds = graphics::screen_coordinates(ctx);
+    graphics::Rect {
+        x: screen_bounds.x + screen_bounds.w - HISTORY_PANEL_WIDTH,
+        y: screen_bounds.y,
+        w: HISTORY_PANEL_WIDTH,
+        h: screen_bounds.h,
+    }
+}
+
+// Nodes are listed one per row in pre-order, indented by depth, so each node's parent is above it.
+pub fn history_node_position(bounds: graphics::Rect, row: usize, depth: usize) -> Point2 {
+    Point2::new(
+        bounds.x + 10. + depth as f32 * HISTORY_INDENT,
+        bounds.y + 10. + row as f32 * HISTORY_ROW_HEIGHT,
+    )
+}
+
+// `labels` are drawn next to the nodes at their paths.
+pub fn history_panel(
+    ctx: &mut ggez::Context,
+    history: &Zipper<HistoryNode, Plan>,
+    labels: &HashMap<Path, String>,
+) -> ggez::GameResult<()> {
+    let bounds = history_panel_bbox(ctx);
+    Mesh::new_rectangle(
+        ctx,
+        graphics::DrawMode::Fill(Default::default()),
+        bounds,
+        graphics::Color::from_rgb(220, 220, 220),
+    )?
+    .draw(ctx, DrawParam::new())?;
+    let black = graphics::BLACK;
+    let mut positions = HashMap::new();
+    for (row, node) in history.dfs().enumerate() {
+        let position = history_node_position(bounds, row, node.path.len());
+        if let Some((_, parent_path)) = node.path.split_last() {
+            let parent = positions[parent_path];
+            let corner = Point2::new(parent.x, position.y);
+            Mesh::new_line(ctx, &[parent, corner, position], 2., black)?
+                .draw(ctx, DrawParam::default())?;
+        }
+        let color = if node.is_focus {
+            graphics::Color::from_rgb(204, 0, 0)
+        } else if !node.val.is_evicted() && node.val.failure().is_some() {
+            graphics::Color::from_rgb(127, 127, 127)
+        } else {
+            black
+        };
+        // Evicted frames are drawn hollow.
+        let draw_mode = if node.val.is_evicted() {
+            graphics::DrawMode::stroke(2.)
+        } else {
+            graphics::DrawMode::Fill(Default::default())
+        };
+        Mesh::new_circle(ctx, draw_mode, position, 5., 0.1, color)?
+            .draw(ctx, DrawParam::default())?;
+        if let Some(plan) = node.edge {
+            graphics::draw(
+                ctx,
+                &graphics::Text::new(plan.summary()),
+                DrawParam::new()
+                    .dest(position + Vector2::new(10., -8.))
+                    .color(black),
+            )?;
+        }
+        if let Some(label) = labels.get(&node.path) {
+            graphics::draw(
+                ctx,
+                &graphics::Text::new(label.as_str()),
+                DrawParam::new()
+                    .dest(position + Vector2::new(120., -8.))
+                    .color(graphics::Color::from_rgb(0, 0, 204)),
+            )?;
+        }
+        positions.insert(node.path, position);
+    }
+    Ok(())
+}
+
+// The path to the history node drawn in the row under `pt`, in a panel drawn in `bounds`.
+pub fn history_panel_node_at(
+    bounds: graphics::Rect,
+    history: &Zipper<HistoryNode, Plan>,
+    pt: Point2,
+) -> Option<Path> {
+    if !bounds.contains(pt) {
+        return None;
+    }
+    let row = (pt.y - bounds.y - 10.) / HISTORY_ROW_HEIGHT + 0.5;
+    if row < 0. {
+        return None;
+    }
+    history.dfs().nth(row as usize).map(|node| node.path)
+}
+
+fn outline_tile(
+    ctx: &mut ggez::Context,
+    bounds: graphics::Rect,
+    pt: Point,
+    color: graphics::Color,
+) -> ggez::GameResult<()> {
+    let pixel_space_pt = tile_space_to_pixel_space(pt, bounds);
+    Mesh::new_rectangle(
+        ctx,
+        graphics::DrawMode::stroke(6.),
+        graphics::Rect::new(
+            pixel_space_pt.x + 3.,
+            pixel_space_pt.y + 3.,
+            SCALE - 6.,
+            SCALE - 6.,
+        ),
+        color,
+    )?
+    .draw(ctx, DrawParam::new())
+}
+
+fn mark_tile_corner(
+    ctx: &mut ggez::Context,
+    bounds: graphics::Rect,
+    pt: Point,
+    color: graphics::Color,
+) -> ggez::GameResult<()> {
+    let pixel_space_pt = tile_space_to_pixel_space(pt, bounds);
+    Mesh::new_rectangle(
+        ctx,
+        graphics::DrawMode::Fill(Default::default()),
+        graphics::Rect::new(
+            pixel_space_pt.x + SCALE - 20.,
+            pixel_space_pt.y + SCALE - 20.,
+            15.,
+            15.,
+        ),
+        color,
+    )?
+    .draw(ctx, DrawParam::new())
+}
+
+// Green for things that moved, appeared or were added, red for things that were removed or taken,
+// blue and purple for portals opening and closing. Changes with no place on the map are listed.
+pub fn frame_diff(ctx: &mut ggez::Context, diff: &FrameDiff) -> ggez::GameResult<()> {
+    let bounds = graphics::screen_coordinates(ctx);
+    let green = graphics::Color::from_rgb(0, 160, 0);
+    let red = graphics::Color::from_rgb(204, 0, 0);
+    let center = |pt| tile_space_to_pixel_space(pt, bounds) + Vector2::new(SCALE / 2., SCALE / 2.);
+    for &(_, old, new) in diff.moved.iter() {
+        Mesh::new_line(ctx, &[center(old), center(new)], 6., green)?
+            .draw(ctx, DrawParam::default())?;
+    }
+    for &(_, position) in diff.appeared.iter() {
+        if let Some(position) = position {
+            outline_tile(ctx, bounds, position, green)?;
+        }
+    }
+    for &(_, position) in diff.removed.iter() {
+        if let Some(position) = position {
+            outline_tile(ctx, bounds, position, red)?;
+        }
+    }
+    for &position in diff.portals_opened.iter() {
+        outline_tile(ctx, bounds, position, graphics::Color::from_rgb(0, 0, 204))?;
+    }
+    for &position in diff.portals_closed.iter() {
+        outline_tile(
+            ctx,
+            bounds,
+            position,
+            graphics::Color::from_rgb(128, 0, 128),
+        )?;
+    }
+    for (_, position) in diff.items_added.iter() {
+        mark_tile_corner(ctx, bounds, *position, green)?;
+    }
+    for (_, position) in diff.items_taken.iter() {
+        mark_tile_corner(ctx, bounds, *position, red)?;
+    }
+    let summary = diff.summary();
+    if !summary.is_empty() {
+        graphics::draw(
+            ctx,
+            &graphics::Text::new(summary.join("\n")),
+            DrawParam::new()
+                .dest(Point2::new(bounds.x + 5., bounds.y + 30.))
+                .color(graphics::BLACK),
+        )?;
+    }
+    Ok(())
+}
@@ -1,6 +1,9 @@
-use std::mem;
+use std::{collections::VecDeque, iter, mem};
 
-#[derive(PartialEq, Eq, Clone)]
+// Child indices leading from the root to a node.
+pub type Path = Vec<usize>;
+
+#[derive(PartialEq, Eq, Clone, Debug)]
 pub struct RoseTree<T, E> {
     pub val: T,
     pub children: Vec<(E, RoseTree<T, E>)>,
@@ -12,6 +15,180 @@ impl<T, E> RoseTree<T, E> {
             children: Vec::new(),
         }
     }
+    // The node at `path` below this one.
+    pub fn get(&self, path: &[usize]) -> Option<&Self> {
+        path.iter().try_fold(self, |tree, &i| {
+            tree.children.get(i).map(|(_, child)| child)
+        })
+    }
+    pub fn get_mut(&mut self, path: &[usize]) -> Option<&mut Self> {
+        path.iter().try_fold(self, |tree, &i| {
+            tree.children.get_mut(i).map(|(_, child)| child)
+        })
+    }
+    // Every value, in depth first pre-order.
+    pub fn dfs(&self) -> Dfs<T, E> {
+        Dfs { stack: vec![self] }
+    }
+    // Every value, a level at a time.
+    pub fn bfs(&self) -> Bfs<T, E> {
+        Bfs {
+            queue: iter::once(self).collect(),
+        }
+    }
+    // Every subtree, including this one, with its path, in depth first pre-order.
+    pub fn paths(&self) -> Paths<T, E> {
+        Paths {
+            stack: vec![(Vec::new(), self)],
+        }
+    }
+    // The values of the nodes without children, with their paths, from left to right.
+    pub fn leaves(&self) -> impl Iterator<Item = (Path, &T)> {
+        self.paths()
+            .filter(|(_, tree)| tree.children.is_empty())
+            .map(|(path, tree)| (path, &tree.val))
+    }
+    // The first value in depth first pre-order that satisfies `pred`, with its path.
+    pub fn find<P: FnMut(&T) -> bool>(&self, mut pred: P) -> Option<(Path, &T)> {
+        self.paths()
+            .map(|(path, tree)| (path, &tree.val))
+            .find(|(_, val)| pred(val))
+    }
+    // The same tree, with `f` applied to every value in depth first pre-order.
+    pub fn map<U, F: FnMut(T) -> U>(self, mut f: F) -> RoseTree<U, E> {
+        self.map_with(&mut f)
+    }
+    fn map_with<U, F: FnMut(T) -> U>(self, f: &mut F) -> RoseTree<U, E> {
+        let val = f(self.val);
+        RoseTree {
+            val,
+            children: map_siblings(self.children, f),
+        }
+    }
+}
+
+fn map_siblings<T, U, E, F: FnMut(T) -> U>(
+    siblings: Vec<(E, RoseTree<T, E>)>,
+    f: &mut F,
+) -> Vec<(E, RoseTree<U, E>)> {
+    siblings
+        .into_iter()
+        .map(|(edge, tree)| (edge, tree.map_with(f)))
+        .collect()
+}
+
+pub struct Dfs<'a, T, E> {
+    stack: Vec<&'a RoseTree<T, E>>,
+}
+
+impl<'a, T, E> Iterator for Dfs<'a, T, E> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        let tree = self.stack.pop()?;
+        self.stack
+            .extend(tree.children.iter().rev().map(|(_, child)| child));
+        Some(&tree.val)
+    }
+}
+
+pub struct Bfs<'a, T, E> {
+    queue: VecDeque<&'a RoseTree<T, E>>,
+}
+
+impl<'a, T, E> Iterator for Bfs<'a, T, E> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
+        let tree = self.queue.pop_front()?;
+        self.queue
+            .extend(tree.children.iter().map(|(_, child)| child));
+        Some(&tree.val)
+    }
+}
+
+pub struct Paths<'a, T, E> {
+    stack: Vec<(Path, &'a RoseTree<T, E>)>,
+}
+
+impl<'a, T, E> Iterator for Paths<'a, T, E> {
+    type Item = (Path, &'a RoseTree<T, E>);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, tree) = self.stack.pop()?;
+        for (i, (_, child)) in tree.children.iter().enumerate().rev() {
+            let mut child_path = path.clone();
+            child_path.push(i);
+            self.stack.push((child_path, child));
+        }
+        Some((path, tree))
+    }
+}
+
+// A node of the tree a zipper represents, found without moving the zipper.
+pub struct NodeRef<'a, T, E> {
+    pub path: Path,
+    pub val: &'a T,
+    // The edge from this node's parent, if it has one.
+    pub edge: Option<&'a E>,
+    pub is_focus: bool,
+}
+
+// A node of the tree a zipper represents: either on the way from the root to the focus, at the
+// given depth, or in one of the subtrees hanging off that way.
+enum ZipperNode<'a, T, E> {
+    Spine(usize),
+    Tree(&'a RoseTree<T, E>),
+}
+
+impl<'a, T, E> Clone for ZipperNode<'a, T, E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T, E> Copy for ZipperNode<'a, T, E> {}
+
+fn subtree<T, E>((edge, tree): &(E, RoseTree<T, E>)) -> (&E, ZipperNode<T, E>) {
+    (edge, ZipperNode::Tree(tree))
+}
+
+// A node still to be visited, with its path and the edge leading to it.
+type Pending<'a, T, E> = (Path, Option<&'a E>, ZipperNode<'a, T, E>);
+
+pub struct ZipperDfs<'a, T, E> {
+    zipper: &'a Zipper<T, E>,
+    stack: Vec<Pending<'a, T, E>>,
+}
+
+impl<'a, T, E> Iterator for ZipperDfs<'a, T, E> {
+    type Item = NodeRef<'a, T, E>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, edge, node) = self.stack.pop()?;
+        let children = self.zipper.children(node);
+        for (i, (child_edge, child)) in children.into_iter().enumerate().rev() {
+            let mut child_path = path.clone();
+            child_path.push(i);
+            self.stack.push((child_path, Some(child_edge), child));
+        }
+        Some(self.zipper.node_ref(path, edge, node))
+    }
+}
+
+pub struct ZipperBfs<'a, T, E> {
+    zipper: &'a Zipper<T, E>,
+    queue: VecDeque<Pending<'a, T, E>>,
+}
+
+impl<'a, T, E> Iterator for ZipperBfs<'a, T, E> {
+    type Item = NodeRef<'a, T, E>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, edge, node) = self.queue.pop_front()?;
+        let children = self.zipper.children(node);
+        for (i, (child_edge, child)) in children.into_iter().enumerate() {
+            let mut child_path = path.clone();
+            child_path.push(i);
+            self.queue.push_back((child_path, Some(child_edge), child));
+        }
+        Some(self.zipper.node_ref(path, edge, node))
+    }
 }
 
 pub struct Zipper<T, E> {
@@ -63,7 +240,7 @@ impl<T, E> Zipper<T, E> {
         });
         Ok(())
     }
-    pub fn up(&mut self) -> Result<usize, &str> {
+    pub fn up(&mut self) -> Result<usize, &'static str> {
         self.frames
             .pop()
             .map(
@@ -89,10 +266,10 @@ impl<T, E> Zipper<T, E> {
             )
             .ok_or("Already at top of zipper")
     }
-    pub fn left(&mut self) -> Result<(), &str> {
+    pub fn left(&mut self) -> Result<(), &'static str> {
         let focus = &mut self.focus;
         self.frames
-            .get_mut(0)
+            .last_mut()
             .and_then(
                 |&mut ZipperFrame {
                      ref mut left,
@@ -109,10 +286,10 @@ impl<T, E> Zipper<T, E> {
             )
             .ok_or("Nothing to the left")
     }
-    pub fn right(&mut self) -> Result<(), &str> {
+    pub fn right(&mut self) -> Result<(), &'static str> {
         let focus = &mut self.focus;
         self.frames
-            .get_mut(0)
+            .last_mut()
             .and_then(
                 |&mut ZipperFrame {
                      ref mut left,
@@ -129,6 +306,180 @@ impl<T, E> Zipper<T, E> {
             )
             .ok_or("Nothing to the right")
     }
+    // The path from the root to the focus.
+    pub fn path(&self) -> Path {
+        self.frames.iter().map(|frame| frame.left.len()).collect()
+    }
+    // Moves the focus to the node at `path`, going only as far up as the deepest common ancestor.
+    // If there's no such node, the focus stays where it was.
+    pub fn go_to(&mut self, path: &[usize]) -> Result<(), &'static str> {
+        let current = self.path();
+        let common = current.iter().zip(path).take_while(|(a, b)| a == b).count();
+        for _ in common..current.len() {
+            self.up().expect("Fewer frames than the path's length");
+        }
+        let (target, return_path) = if self.focus.get(&path[common..]).is_some() {
+            (Ok(()), &path[common..])
+        } else {
+            (Err("No node at that path"), &current[common..])
+        };
+        for &i in return_path {
+            self.down(i).expect("Checked that the path exists");
+        }
+        target
+    }
+    // Every node in the tree, in depth first pre-order.
+    pub fn dfs(&self) -> ZipperDfs<T, E> {
+        ZipperDfs {
+            zipper: self,
+            stack: vec![(Vec::new(), None, ZipperNode::Spine(0))],
+        }
+    }
+    // Every node in the tree, a level at a time.
+    pub fn bfs(&self) -> ZipperBfs<T, E> {
+        ZipperBfs {
+            zipper: self,
+            queue: iter::once((Vec::new(), None, ZipperNode::Spine(0))).collect(),
+        }
+    }
+    // The path to every node in the tree and its value, in depth first pre-order.
+    pub fn paths(&self) -> impl Iterator<Item = (Path, &T)> {
+        self.dfs().map(|node| (node.path, node.val))
+    }
+    // The children of `node`, in order, with the edges leading to them.
+    fn children<'a>(&'a self, node: ZipperNode<'a, T, E>) -> Vec<(&'a E, ZipperNode<'a, T, E>)> {
+        let tree = match node {
+            ZipperNode::Tree(tree) => tree,
+            ZipperNode::Spine(level) => match self.frames.get(level) {
+                Some(frame) => {
+                    return frame
+                        .left
+                        .iter()
+                        .map(subtree)
+                        .chain(iter::once((&frame.edge, ZipperNode::Spine(level + 1))))
+                        .chain(frame.right.iter().rev().map(subtree))
+                        .collect()
+                }
+                None => &self.focus,
+            },
+        };
+        tree.children.iter().map(subtree).collect()
+    }
+    fn node_ref<'a>(
+        &'a self,
+        path: Path,
+        edge: Option<&'a E>,
+        node: ZipperNode<'a, T, E>,
+    ) -> NodeRef<'a, T, E> {
+        let (val, is_focus) = match node {
+            ZipperNode::Tree(tree) => (&tree.val, false),
+            ZipperNode::Spine(level) => match self.frames.get(level) {
+                Some(frame) => (&frame.val, false),
+                None => (&self.focus.val, true),
+            },
+        };
+        NodeRef {
+            path,
+            val,
+            edge,
+            is_focus,
+        }
+    }
+    // The nodes without children, from left to right.
+    pub fn leaves(&self) -> impl Iterator<Item = NodeRef<T, E>> {
+        let mut nodes = self.dfs().peekable();
+        iter::from_fn(move || loop {
+            let node = nodes.next()?;
+            // In pre-order, a node's children come straight after it.
+            match nodes.peek() {
+                Some(next) if next.path.len() > node.path.len() => {}
+                _ => return Some(node),
+            }
+        })
+    }
+    // The first node in depth first pre-order whose value satisfies `pred`.
+    pub fn find<P: FnMut(&T) -> bool>(&self, mut pred: P) -> Option<NodeRef<T, E>> {
+        self.dfs().find(|node| pred(node.val))
+    }
+    // The same zipper, focused on the same node, with `f` applied to every value.
+    pub fn map<U, F: FnMut(T) -> U>(self, mut f: F) -> Zipper<U, E> {
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|frame| ZipperFrame {
+                val: f(frame.val),
+                edge: frame.edge,
+                left: map_siblings(frame.left, &mut f),
+                right: map_siblings(frame.right, &mut f),
+            })
+            .collect();
+        Zipper {
+            frames,
+            focus: self.focus.map_with(&mut f),
+        }
+    }
+    // The values above the focus, from the root down, each with the edge leading towards the focus.
+    pub fn ancestors(&self) -> impl DoubleEndedIterator<Item = (&T, &E)> {
+        self.frames.iter().map(|frame| (&frame.val, &frame.edge))
+    }
+    // Calls `f` on every node in the tree, with its path.
+    pub fn for_each_mut<F: FnMut(&[usize], &mut T)>(&mut self, mut f: F) {
+        fn visit_tree<T, E, F: FnMut(&[usize], &mut T)>(
+            tree: &mut RoseTree<T, E>,
+            path: &mut Path,
+            f: &mut F,
+        ) {
+            f(path, &mut tree.val);
+            for (i, (_, child)) in tree.children.iter_mut().enumerate() {
+                path.push(i);
+                visit_tree(child, path, f);
+                path.pop();
+            }
+        }
+        let mut path = Vec::new();
+        for frame in self.frames.iter_mut() {
+            f(&path, &mut frame.val);
+            let ix = frame.left.len();
+            for (i, (_, child)) in frame.left.iter_mut().enumerate() {
+                path.push(i);
+                visit_tree(child, &mut path, &mut f);
+                path.pop();
+            }
+            for (i, (_, child)) in frame.right.iter_mut().rev().enumerate() {
+                path.push(ix + 1 + i);
+                visit_tree(child, &mut path, &mut f);
+                path.pop();
+            }
+            path.push(ix);
+        }
+        visit_tree(&mut self.focus, &mut path, &mut f);
+    }
+    // Removes the focus and everything below it, moving the focus up to its parent. Returns the
+    // removed subtree along with the edge that led to it.
+    pub fn delete_focus(&mut self) -> Result<(E, RoseTree<T, E>), &'static str> {
+        let ZipperFrame {
+            val,
+            edge,
+            mut left,
+            right,
+        } = self.frames.pop().ok_or("Can't delete the root")?;
+        left.extend(right.into_iter().rev());
+        let old_focus = mem::replace(
+            &mut self.focus,
+            RoseTree {
+                val,
+                children: left,
+            },
+        );
+        Ok((edge, old_focus))
+    }
+    // Removes every branch that doesn't lead to the focus. The focus's own subtree is kept.
+    pub fn prune_to_path(&mut self) {
+        for frame in self.frames.iter_mut() {
+            frame.left.clear();
+            frame.right.clear();
+        }
+    }
     pub fn rezip(mut self) -> RoseTree<T, E> {
         while self.up().is_ok() {}
         self.focus
@@ -145,3 +496,150 @@ impl<T, E> Zipper<T, E> {
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{RoseTree, Zipper};
+    use proptest::{collection, prelude::*};
+
+    fn arbitrary_tree() -> BoxedStrategy<RoseTree<u8, u8>> {
+        any::<u8>()
+            .prop_map(RoseTree::singleton)
+            .prop_recursive(4, 64, 4, |inner| {
+                (any::<u8>(), collection::vec((any::<u8>(), inner), 0..4))
+                    .prop_map(|(val, children)| RoseTree { val, children })
+            })
+            .boxed()
+    }
+
+    #[derive(Clone, Debug)]
+    enum Step {
+        Down(usize),
+        Up,
+        Left,
+        Right,
+    }
+
+    fn arbitrary_step() -> BoxedStrategy<Step> {
+        prop_oneof![
+            (0usize..4).prop_map(Step::Down),
+            Just(Step::Up),
+            Just(Step::Left),
+            Just(Step::Right),
+        ]
+        .boxed()
+    }
+
+    // Takes each step that's possible, ignoring the rest.
+    fn navigate(zipper: &mut Zipper<u8, u8>, steps: &[Step]) {
+        for step in steps {
+            // Impossible steps fail without moving the zipper.
+            let _ = match step {
+                Step::Down(i) => zipper.down(*i),
+                Step::Up => zipper.up().map(|_| ()),
+                Step::Left => zipper.left(),
+                Step::Right => zipper.right(),
+            };
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_navigation_preserves_tree(tree in arbitrary_tree(), steps in collection::vec(arbitrary_step(), 0..32)) {
+            let mut zipper = Zipper::new(tree.clone());
+            for step in &steps {
+                navigate(&mut zipper, std::slice::from_ref(step));
+                prop_assert_eq!(Some(&zipper.focus), tree.get(&zipper.path()));
+            }
+            prop_assert_eq!(zipper.rezip(), tree);
+        }
+
+        #[test]
+        fn test_zipper_traversals_match_tree(tree in arbitrary_tree(), steps in collection::vec(arbitrary_step(), 0..32)) {
+            let mut zipper = Zipper::new(tree.clone());
+            navigate(&mut zipper, &steps);
+            let paths: Vec<_> = zipper.paths().map(|(path, val)| (path, *val)).collect();
+            let tree_paths: Vec<_> = tree.paths().map(|(path, node)| (path, node.val)).collect();
+            prop_assert_eq!(&paths, &tree_paths);
+            let dfs: Vec<_> = zipper.dfs().map(|node| *node.val).collect();
+            prop_assert_eq!(dfs, tree.dfs().cloned().collect::<Vec<_>>());
+            let bfs: Vec<_> = zipper.bfs().map(|node| *node.val).collect();
+            prop_assert_eq!(bfs, tree.bfs().cloned().collect::<Vec<_>>());
+            let leaves: Vec<_> = zipper.leaves().map(|node| (node.path, *node.val)).collect();
+            let tree_leaves: Vec<_> = tree.leaves().map(|(path, val)| (path, *val)).collect();
+            prop_assert_eq!(leaves, tree_leaves);
+            let found = zipper.find(|val| val % 3 == 0).map(|node| (node.path, *node.val));
+            prop_assert_eq!(found, tree.find(|val| val % 3 == 0).map(|(path, val)| (path, *val)));
+            let path = zipper.path();
+            let zipper = zipper.map(u16::from);
+            prop_assert_eq!(zipper.path(), path);
+            prop_assert_eq!(zipper.rezip(), tree.map(u16::from));
+        }
+    }
+
+    #[test]
+    fn test_traversal_orders() {
+        let mut zipper = Zipper::new(RoseTree::singleton(0));
+        zipper.push(1, 'a');
+        zipper.push(3, 'c');
+        zipper.up().expect("Couldn't go up");
+        zipper.up().expect("Couldn't go up");
+        zipper.push(2, 'b');
+        let tree = zipper.rezip();
+        assert_eq!(tree.dfs().cloned().collect::<Vec<_>>(), vec![0, 1, 3, 2]);
+        assert_eq!(tree.bfs().cloned().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        let leaves: Vec<_> = tree.leaves().map(|(path, val)| (path, *val)).collect();
+        assert_eq!(leaves, vec![(vec![0, 0], 3), (vec![1], 2)]);
+        assert_eq!(
+            tree.find(|&val| val > 2).map(|(path, _)| path),
+            Some(vec![0, 0])
+        );
+        assert_eq!(tree.find(|&val| val > 3), None);
+    }
+
+    #[test]
+    fn test_go_to_across_branches() {
+        let mut zipper = Zipper::new(RoseTree::singleton(0));
+        zipper.push(1, 'a');
+        zipper.push(2, 'b');
+        zipper.up().expect("Couldn't go up");
+        zipper.up().expect("Couldn't go up");
+        zipper.push(3, 'c');
+        assert_eq!(zipper.path(), vec![1]);
+        zipper.go_to(&[0, 0]).expect("Couldn't go to existing node");
+        assert_eq!(*zipper.get_focus_val(), 2);
+        assert_eq!(zipper.path(), vec![0, 0]);
+        zipper.go_to(&[1, 0]).expect_err("Went to a missing node");
+        assert_eq!(*zipper.get_focus_val(), 2);
+        let tree = zipper.rezip();
+        assert_eq!(tree.get(&[1]).map(|node| node.val), Some(3));
+    }
+
+    #[test]
+    fn test_delete_and_prune() {
+        let mut zipper = Zipper::new(RoseTree::singleton(0));
+        zipper.push(1, 'a');
+        zipper.up().expect("Couldn't go up");
+        zipper.push(2, 'b');
+        zipper.push(3, 'c');
+        zipper.up().expect("Couldn't go up");
+        zipper.up().expect("Couldn't go up");
+        zipper.push(4, 'd');
+        // Every node is visited with the same path `dfs` reports for it.
+        let mut visited = Vec::new();
+        zipper.for_each_mut(|path, val| visited.push((path.to_vec(), *val)));
+        visited.sort();
+        let mut nodes: Vec<_> = zipper.dfs().map(|node| (node.path, *node.val)).collect();
+        nodes.sort();
+        assert_eq!(visited, nodes);
+        let (edge, deleted) = zipper.delete_focus().expect("Couldn't delete");
+        assert_eq!((edge, deleted.val), ('d', 4));
+        assert_eq!(zipper.focus.children.len(), 2);
+        zipper.go_to(&[1, 0]).expect("Couldn't go to existing node");
+        zipper.prune_to_path();
+        assert_eq!(zipper.path(), vec![0, 0]);
+        let tree = zipper.rezip();
+        assert_eq!(tree.children.len(), 1);
+        assert_eq!(tree.get(&[0, 0]).map(|node| node.val), Some(3));
+    }
+}
@@ -1,23 +1,21 @@
 use super::ggez::{graphics, nalgebra};
-use enum_map::EnumMap;
 use enumset::EnumSet;
-use slotmap::{HopSlotMap, SecondaryMap, SparseSecondaryMap};
+use im_rc::{hashmap, Vector};
 use std::{
     borrow::Cow,
-    cmp::Ordering,
-    collections::{
-        hash_map::{self, Entry},
-        HashMap, HashSet,
-    },
-    fmt,
+    cmp::{min, Ordering},
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt, iter,
     marker::PhantomData,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, Index, IndexMut},
     rc::Rc,
 };
 
 pub const SCALE: f32 = 100.;
 pub const INVENTORY_WIDTH: usize = 8;
 pub const INVENTORY_HEIGHT: usize = 4;
+// How many tiles a thrown item can fly.
+pub const THROW_RANGE: i32 = 4;
 
 //Why `Id<T>`s instead of some sort of reference? The fundamental problem, I think, is that a given
 //`Id<Player>` referes to multiple different `Player`s, since each `GameFrame` has a different
@@ -55,33 +53,44 @@ impl<T> Clone for Id<T> {
 
 impl<T> Copy for Id<T> {}
 
-impl<T> rand::Rand for Id<T> {
-    fn rand<R: rand::Rng>(rng: &mut R) -> Self {
-        Id(rand::Rand::rand(rng), PhantomData)
-    }
-}
 impl<T> fmt::Debug for Id<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Id::new({})", self.0)
     }
 }
 
-type IdMap<T> = HashMap<Id<T>, T>;
+// Hands out ids in order. Every frame carries its own, so applying the same plan to the same frame
+// always hands out the same ids, and frames recomputed from their plans match the originals.
+#[derive(Clone, Debug, Default)]
+pub struct IdGen(u64);
+impl IdGen {
+    pub fn new() -> Self {
+        IdGen(0)
+    }
+    pub fn fresh<T>(&mut self) -> Id<T> {
+        self.0 += 1;
+        Id(self.0, PhantomData)
+    }
+}
+
+// The maps below are persistent, so cloning a frame shares them with the original until one of the
+// copies changes them.
+type IdMap<T> = im_rc::HashMap<Id<T>, T>;
 
 #[derive(Clone, Debug, Default)]
-pub struct DoubleMap<T> {
+pub struct DoubleMap<T: Clone> {
     by_id: IdMap<T>,
-    by_position: HashMap<Point, Id<T>>,
+    by_position: im_rc::HashMap<Point, Id<T>>,
 }
 #[allow(clippy::trivially_copy_pass_by_ref)]
-impl<T> DoubleMap<T> {
+impl<T: Clone> DoubleMap<T> {
     pub fn new() -> Self {
         DoubleMap {
-            by_id: HashMap::new(),
-            by_position: HashMap::new(),
+            by_id: im_rc::HashMap::new(),
+            by_position: im_rc::HashMap::new(),
         }
     }
-    pub fn iter<'a, 'b: 'a>(&'b self) -> hash_map::Iter<'a, Id<T>, T> {
+    pub fn iter<'a, 'b: 'a>(&'b self) -> hashmap::Iter<'a, Id<T>, T> {
         self.by_id.iter()
     }
     pub fn contains_id(&self, id: &Id<T>) -> bool {
@@ -113,12 +122,12 @@ impl<T> DoubleMap<T> {
 
 pub type GameError = Cow<'static, str>;
 
-pub struct DoubleMapRef<'a, T: DoubleMappable> {
+pub struct DoubleMapRef<'a, T: DoubleMappable + Clone> {
     value: Option<T>,
     map: &'a mut DoubleMap<T>,
 }
 
-impl<'a, T: DoubleMappable> Deref for DoubleMapRef<'a, T> {
+impl<'a, T: DoubleMappable + Clone> Deref for DoubleMapRef<'a, T> {
     type Target = T;
     fn deref(&self) -> &T {
         self.value
@@ -127,7 +136,7 @@ impl<'a, T: DoubleMappable> Deref for DoubleMapRef<'a, T> {
     }
 }
 
-impl<'a, T: DoubleMappable> DerefMut for DoubleMapRef<'a, T> {
+impl<'a, T: DoubleMappable + Clone> DerefMut for DoubleMapRef<'a, T> {
     fn deref_mut(&mut self) -> &mut T {
         self.value
             .as_mut()
@@ -135,7 +144,7 @@ impl<'a, T: DoubleMappable> DerefMut for DoubleMapRef<'a, T> {
     }
 }
 
-impl<'a, T: DoubleMappable> Drop for DoubleMapRef<'a, T> {
+impl<'a, T: DoubleMappable + Clone> Drop for DoubleMapRef<'a, T> {
     fn drop(&mut self) {
         let v = self
             .value
@@ -150,18 +159,18 @@ impl<'a, T: DoubleMappable> Drop for DoubleMapRef<'a, T> {
 #[allow(clippy::trivially_copy_pass_by_ref)]
 impl<T> DoubleMap<T>
 where
-    T: DoubleMappable,
+    T: DoubleMappable + Clone,
 {
     pub fn insert(&mut self, t: T) -> Result<(), GameError> {
         match self.by_position.entry(t.position()) {
-            Entry::Occupied(_) => {
+            hashmap::Entry::Occupied(_) => {
                 Err("Position occupied")?;
             }
-            Entry::Vacant(position_entry) => match self.by_id.entry(t.id()) {
-                Entry::Occupied(_) => {
+            hashmap::Entry::Vacant(position_entry) => match self.by_id.entry(t.id()) {
+                hashmap::Entry::Occupied(_) => {
                     Err("Id already exists")?;
                 }
-                Entry::Vacant(id_entry) => {
+                hashmap::Entry::Vacant(id_entry) => {
                     position_entry.insert(t.id());
                     id_entry.insert(t);
                 }
@@ -206,6 +215,112 @@ impl DoubleMappable for ItemDrop {
     }
 }
 
+// Like a `DoubleMap`, but any number of values can share a position. Each position keeps its values
+// in the order they arrived, so the last one is the top of the stack.
+#[derive(Clone, Debug, Default)]
+pub struct StackMap<T: Clone> {
+    by_id: IdMap<T>,
+    by_position: im_rc::HashMap<Point, Vec<Id<T>>>,
+}
+#[allow(clippy::trivially_copy_pass_by_ref)]
+impl<T: Clone> StackMap<T> {
+    pub fn new() -> Self {
+        StackMap {
+            by_id: im_rc::HashMap::new(),
+            by_position: im_rc::HashMap::new(),
+        }
+    }
+    pub fn iter<'a, 'b: 'a>(&'b self) -> hashmap::Iter<'a, Id<T>, T> {
+        self.by_id.iter()
+    }
+    // Every non-empty stack, from the bottom up.
+    pub fn stacks(&self) -> impl Iterator<Item = (Point, Vec<&T>)> + '_ {
+        self.by_position
+            .keys()
+            .map(move |pos| (*pos, self.stack_at(pos)))
+    }
+    pub fn contains_id(&self, id: &Id<T>) -> bool {
+        self.by_id.contains_key(id)
+    }
+    pub fn get_by_id<'a, 'b: 'a>(&'b self, id: &Id<T>) -> Option<&'a T> {
+        self.by_id.get(id)
+    }
+    // The top of the stack at `pos`.
+    pub fn get_by_position<'a, 'b: 'a>(&'b self, pos: &Point) -> Option<&'a T> {
+        self.by_position
+            .get(pos)
+            .and_then(|stack| stack.last())
+            .map(|id| self.by_id.get(id).expect("StackMap inconsistent"))
+    }
+    // Everything at `pos`, from the bottom of the stack up.
+    pub fn stack_at<'a, 'b: 'a>(&'b self, pos: &Point) -> Vec<&'a T> {
+        self.by_position.get(pos).map_or_else(Vec::new, |stack| {
+            stack
+                .iter()
+                .map(|id| self.by_id.get(id).expect("StackMap inconsistent"))
+                .collect()
+        })
+    }
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+impl<T> StackMap<T>
+where
+    T: DoubleMappable + Clone,
+{
+    // Puts `t` on top of the stack at its position.
+    pub fn insert(&mut self, t: T) -> Result<(), GameError> {
+        match self.by_id.entry(t.id()) {
+            hashmap::Entry::Occupied(_) => {
+                Err("Id already exists")?;
+            }
+            hashmap::Entry::Vacant(id_entry) => {
+                self.by_position
+                    .entry(t.position())
+                    .or_insert_with(Vec::new)
+                    .push(t.id());
+                id_entry.insert(t);
+            }
+        }
+        Ok(())
+    }
+    pub fn remove_by_id(&mut self, id: &Id<T>) -> Option<T> {
+        let t = self.by_id.remove(id)?;
+        let position = t.position();
+        let stack = self
+            .by_position
+            .get_mut(&position)
+            .expect("StackMap inconsistent");
+        stack.retain(|other| other != id);
+        if stack.is_empty() {
+            self.by_position.remove(&position);
+        }
+        Some(t)
+    }
+    // Removes the `ix`th value from the bottom of the stack at `pos`, or the top if `ix` is `None`.
+    pub fn remove_by_position(&mut self, pos: &Point, ix: Option<usize>) -> Option<T> {
+        let stack = self.by_position.get(pos)?;
+        let id = match ix {
+            Some(ix) => *stack.get(ix)?,
+            None => *stack.last()?,
+        };
+        self.remove_by_id(&id)
+    }
+    // Lets `f` modify a value, moving it to the top of its new stack if its position changed.
+    pub fn update_by_id<F: FnOnce(&mut T)>(&mut self, id: &Id<T>, f: F) -> Option<()> {
+        let mut t = self.remove_by_id(id)?;
+        f(&mut t);
+        self.insert(t).expect("Id was just removed");
+        Some(())
+    }
+}
+
 #[derive(PartialEq, Eq)]
 pub enum Selection {
     Top,
@@ -239,6 +354,11 @@ pub struct ImageMap {
     pub jump_icon: DrawRef,
     pub pick_up_icon: DrawRef,
     pub drop_icon: DrawRef,
+    pub use_icon: DrawRef,
+    pub wait_icon: DrawRef,
+    pub give_icon: DrawRef,
+    pub throw_icon: DrawRef,
+    pub unplanned_icon: DrawRef,
     pub portal: DrawRef,
     pub key: DrawRef,
     pub wall: DrawRef,
@@ -246,6 +366,19 @@ pub struct ImageMap {
     pub closed_door: DrawRef,
     pub plate: DrawRef,
     pub lights: [DrawRef; 4],
+    pub spikes: DrawRef,
+    pub pit: DrawRef,
+    pub death: DrawRef,
+    pub guard: DrawRef,
+    pub platform: DrawRef,
+    pub lever: DrawRef,
+    pub lever_on: DrawRef,
+    pub switch: DrawRef,
+    pub switch_on: DrawRef,
+    pub teleporter: DrawRef,
+    pub conveyor: DrawRef,
+    pub ice: DrawRef,
+    pub one_way: DrawRef,
 }
 
 fn load_image(ctx: &mut ggez::Context, path: &str) -> ggez::GameResult<DrawRef> {
@@ -261,6 +394,11 @@ impl ImageMap {
         let jump_icon = load_image(ctx, "/images/jump.png")?;
         let pick_up_icon = load_image(ctx, "/images/pick_up.png")?;
         let drop_icon = load_image(ctx, "/images/drop.png")?;
+        let use_icon = load_image(ctx, "/images/use.png")?;
+        let wait_icon = load_image(ctx, "/images/wait.png")?;
+        let give_icon = load_image(ctx, "/images/give.png")?;
+        let throw_icon = load_image(ctx, "/images/throw.png")?;
+        let unplanned_icon = load_image(ctx, "/images/unplanned.png")?;
         let portal = load_image(ctx, "/images/portal.png")?;
         let key = load_image(ctx, "/images/key.png")?;
         let wall = load_image(ctx, "/images/wall.png")?;
@@ -273,6 +411,19 @@ impl ImageMap {
             load_image(ctx, "/images/lights2.png")?,
             load_image(ctx, "/images/lights3.png")?,
         ];
+        let spikes = load_image(ctx, "/images/spikes.png")?;
+        let pit = load_image(ctx, "/images/pit.png")?;
+        let death = load_image(ctx, "/images/death.png")?;
+        let guard = load_image(ctx, "/images/guard.png")?;
+        let platform = load_image(ctx, "/images/platform.png")?;
+        let lever = load_image(ctx, "/images/lever.png")?;
+        let lever_on = load_image(ctx, "/images/lever_on.png")?;
+        let switch = load_image(ctx, "/images/switch.png")?;
+        let switch_on = load_image(ctx, "/images/switch_on.png")?;
+        let teleporter = load_image(ctx, "/images/teleporter.png")?;
+        let conveyor = load_image(ctx, "/images/conveyor.png")?;
+        let ice = load_image(ctx, "/images/ice.png")?;
+        let one_way = load_image(ctx, "/images/one_way.png")?;
         Ok(ImageMap {
             player,
             selection,
@@ -280,6 +431,11 @@ impl ImageMap {
             jump_icon,
             pick_up_icon,
             drop_icon,
+            use_icon,
+            wait_icon,
+            give_icon,
+            throw_icon,
+            unplanned_icon,
             portal,
             key,
             wall,
@@ -287,6 +443,19 @@ impl ImageMap {
             closed_door,
             plate,
             lights,
+            spikes,
+            pit,
+            death,
+            guard,
+            platform,
+            lever,
+            lever_on,
+            switch,
+            switch_on,
+            teleporter,
+            conveyor,
+            ice,
+            one_way,
         })
     }
     pub fn mock() -> Self {
@@ -298,6 +467,11 @@ impl ImageMap {
             jump_icon: empty_image,
             pick_up_icon: empty_image,
             drop_icon: empty_image,
+            use_icon: empty_image,
+            wait_icon: empty_image,
+            give_icon: empty_image,
+            throw_icon: empty_image,
+            unplanned_icon: empty_image,
             portal: empty_image,
             key: empty_image,
             wall: empty_image,
@@ -305,6 +479,19 @@ impl ImageMap {
             closed_door: empty_image,
             plate: empty_image,
             lights: [empty_image; 4],
+            spikes: empty_image,
+            pit: empty_image,
+            death: empty_image,
+            guard: empty_image,
+            platform: empty_image,
+            lever: empty_image,
+            lever_on: empty_image,
+            switch: empty_image,
+            switch_on: empty_image,
+            teleporter: empty_image,
+            conveyor: empty_image,
+            ice: empty_image,
+            one_way: empty_image,
         }
     }
 }
@@ -337,10 +524,10 @@ pub struct Portal {
 }
 
 impl Portal {
-    pub fn new(timestamp: usize, player_position: Point) -> Self {
+    pub fn new(ids: &mut IdGen, timestamp: usize, player_position: Point) -> Self {
         Portal {
             timestamp,
-            id: rand::random(),
+            id: ids.fresh(),
             player_position,
         }
     }
@@ -354,15 +541,44 @@ pub enum Direction {
     Right,
 }
 
-#[derive(Clone, Debug)]
+impl Direction {
+    pub fn delta(self) -> nalgebra::Vector2<i32> {
+        match self {
+            Direction::Up => -nalgebra::Vector2::y(),
+            Direction::Down => nalgebra::Vector2::y(),
+            Direction::Left => -nalgebra::Vector2::x(),
+            Direction::Right => nalgebra::Vector2::x(),
+        }
+    }
+    pub fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Move {
     Direction(Direction),
     Jump,
-    PickUp,
+    // Pick up the item at the given index from the bottom of the stack on the player's tile, or
+    // the top one if there's no index.
+    PickUp(Option<usize>),
     Drop(usize),
+    // Interact with whatever's on the player's tile.
+    Use,
+    // Deliberately stay put, as opposed to having no move planned yet.
+    Wait,
+    // Hand the item at the given inventory index to a player on the same or an adjacent tile.
+    Give(usize, Entity),
+    // Throw the item at the given inventory index, see `throw_landing`.
+    Throw(usize, Direction),
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
 pub struct Plan {
     pub moves: HashMap<Entity, Move>,
     pub portals: HashSet<Point>,
@@ -375,11 +591,38 @@ impl Plan {
             portals: HashSet::new(),
         }
     }
+    // A short label for the plan, one character per move and one `Q` per portal.
+    pub fn summary(&self) -> String {
+        let mut moves: Vec<char> = self
+            .moves
+            .values()
+            .map(|mv| match mv {
+                Move::Direction(Direction::Up) => '^',
+                Move::Direction(Direction::Down) => 'v',
+                Move::Direction(Direction::Left) => '<',
+                Move::Direction(Direction::Right) => '>',
+                Move::Jump => 'J',
+                Move::PickUp(_) => 'G',
+                Move::Drop(_) => 'D',
+                Move::Use => 'E',
+                Move::Wait => '.',
+                Move::Give(_, _) => 'Y',
+                Move::Throw(_, _) => 'T',
+            })
+            .collect();
+        moves.sort_unstable();
+        moves.extend(iter::repeat('Q').take(self.portals.len()));
+        moves.into_iter().collect()
+    }
 }
 
+#[derive(Clone, Debug)]
 pub enum CachablePlan {
     Novel(Plan),
     Old(usize),
+    // A copy of an old plan that's since been changed. Stepping with it starts a new branch, like a
+    // novel plan, but it can also be rebased to replace the old plan.
+    Edited(usize, Plan),
 }
 
 impl Default for CachablePlan {
@@ -394,17 +637,30 @@ impl CachablePlan {
     }
     pub fn get<'a, T>(&'a self, history_children: &'a [(Plan, T)]) -> &'a Plan {
         match *self {
-            CachablePlan::Novel(ref p) => &p,
+            CachablePlan::Novel(ref p) | CachablePlan::Edited(_, ref p) => &p,
             CachablePlan::Old(ix) => &history_children[ix].0,
         }
     }
     pub fn cow<'a, T>(&'a mut self, history_children: &'a [(Plan, T)]) -> &'a mut Plan {
         if let CachablePlan::Old(ix) = *self {
-            *self = CachablePlan::Novel(history_children[ix].0.clone());
+            *self = CachablePlan::Edited(ix, history_children[ix].0.clone());
         }
         match *self {
-            CachablePlan::Novel(ref mut plan) => plan,
-            _ => panic!("Just set the plan to be novel"),
+            CachablePlan::Novel(ref mut plan) | CachablePlan::Edited(_, ref mut plan) => plan,
+            CachablePlan::Old(_) => panic!("Just copied the old plan"),
+        }
+    }
+    // Swaps a plan that's the same as a recorded one for the recorded one, so stepping with it
+    // follows that branch instead of starting a duplicate.
+    pub fn dedup<T>(&mut self, history_children: &[(Plan, T)]) {
+        let ix = match *self {
+            CachablePlan::Novel(ref plan) | CachablePlan::Edited(_, ref plan) => history_children
+                .iter()
+                .position(|(old_plan, _)| old_plan == plan),
+            CachablePlan::Old(_) => None,
+        };
+        if let Some(ix) = ix {
+            *self = CachablePlan::Old(ix);
         }
     }
 }
@@ -828,9 +1084,9 @@ pub struct ItemDrop {
     pub item: Item,
 }
 impl ItemDrop {
-    pub fn new(item: Item, position: Point) -> Self {
+    pub fn new(ids: &mut IdGen, item: Item, position: Point) -> Self {
         ItemDrop {
-            id: rand::random(),
+            id: ids.fresh(),
             item,
             position,
         }
@@ -845,15 +1101,54 @@ pub enum MapElement {
     RemoteDoor,
     OpenDoor,
     Plate(Counter, Entity),
+    // Lights up in proportion to how close `counter` is to `threshold`. Fires `rising` when it
+    // reaches the threshold, and `falling` when it drops back below.
     Light {
         counter: Counter,
+        threshold: i64,
         rising: Action,
         falling: Action,
     },
     MovingWall {
-        direction: Direction,
-        reset: Option<(Point, Point)>,
+        path: Patrol,
+        crushing: bool,
+    },
+    Spikes,
+    Pit,
+    Guard {
+        path: Patrol,
+        facing: Direction,
+        range: i32,
+    },
+    // Carries any player standing on it along its path.
+    Platform(Patrol),
+    // Flips between `on` and `off` every time it's used.
+    Lever {
+        on: Action,
+        off: Action,
     },
+    // Latches on the first time it's used.
+    Switch(Action),
+    // Invisible wiring: fires `rising` when its output turns on, and `falling` when it turns off.
+    Gate {
+        kind: GateKind,
+        inputs: Vec<(Entity, Counter)>,
+        rising: Action,
+        falling: Action,
+    },
+    // Sends players who walk onto it, and items dropped on it, to the given point. Usually paired
+    // with another teleporter there that points back.
+    Teleporter(Point),
+    // Pushes players and items on it one tile per turn.
+    Conveyor(Direction),
+    // Players who step onto ice keep sliding until they'd hit something.
+    Ice,
+    // Can only be entered or left while moving in the given direction. Only stops players and
+    // conveyed items: moving walls, platforms and guards follow their paths regardless.
+    OneWay(Direction),
+    // A thin wall along the given side of the tile. The tile itself stays walkable. Stops players
+    // and items, but not moving walls, platforms or guards, which follow their paths regardless.
+    EdgeWall(Direction),
 }
 impl MapElement {
     pub fn image(&self, image_map: &ImageMap) -> Option<DrawRef> {
@@ -865,6 +1160,18 @@ impl MapElement {
             MapElement::Plate(_, _) => Some(image_map.plate),
             MapElement::Light { .. } => Some(image_map.lights[0]),
             MapElement::MovingWall { .. } => Some(image_map.wall),
+            MapElement::Spikes => Some(image_map.spikes),
+            MapElement::Pit => Some(image_map.pit),
+            MapElement::Guard { .. } => Some(image_map.guard),
+            MapElement::Platform(_) => Some(image_map.platform),
+            MapElement::Lever { .. } => Some(image_map.lever),
+            MapElement::Switch(_) => Some(image_map.switch),
+            MapElement::Gate { .. } => None,
+            MapElement::Teleporter(_) => Some(image_map.teleporter),
+            MapElement::Conveyor(_) => Some(image_map.conveyor),
+            MapElement::Ice => Some(image_map.ice),
+            MapElement::OneWay(_) => Some(image_map.one_way),
+            MapElement::EdgeWall(_) => None,
         }
     }
     pub fn passable(&self) -> bool {
@@ -874,13 +1181,25 @@ impl MapElement {
             | MapElement::ClosedDoor // Dealt with later
             | MapElement::RemoteDoor // Dealt with later
             | MapElement::Plate(_, _)
-            | MapElement::Light{..} => true,
+            | MapElement::Light{..}
+            | MapElement::Spikes // Lethal, but you can still walk into them
+            | MapElement::Pit
+            | MapElement::Guard {..}
+            | MapElement::Platform(_)
+            | MapElement::Lever { .. }
+            | MapElement::Switch(_)
+            | MapElement::Gate { .. }
+            | MapElement::Teleporter(_)
+            | MapElement::Conveyor(_)
+            | MapElement::Ice
+            | MapElement::OneWay(_) // Dealt with in `move_blocked`
+            | MapElement::EdgeWall(_) => true,
             MapElement::Wall
             | MapElement::MovingWall {..}=> false,
         }
     }
     pub fn add(&self, image_map: &ImageMap, pt: Point, ecs: &mut ECS) -> Entity {
-        let e = ecs.entities.insert(());
+        let e = ecs.entities.insert();
         if let Some(image) = self.image(image_map) {
             ecs.images.insert(e, image);
         }
@@ -921,31 +1240,45 @@ impl MapElement {
             }
             MapElement::Light {
                 counter,
+                threshold,
                 rising,
                 falling,
             } => {
-                event_listeners.extend((0..4).map(|i| {
+                let threshold = *threshold;
+                assert!(threshold > 0, "Light threshold must be positive");
+                let last_light = image_map.lights.len() - 1;
+                event_listeners.extend((0..=threshold).map(|i| {
+                    let predicate: Rc<Box<dyn Fn(i64) -> bool>> = if i == 0 {
+                        Rc::new(Box::new(|c| c <= 0))
+                    } else if i == threshold {
+                        Rc::new(Box::new(move |c| c >= threshold))
+                    } else {
+                        Rc::new(Box::new(move |c| c == i))
+                    };
                     EventListener::new(
-                        EventTrigger::CounterPredicate(
-                            *counter,
-                            Rc::new(Box::new(move |c| c == i)),
-                        ),
+                        EventTrigger::CounterPredicate(*counter, predicate),
                         Action::SetImage {
                             target: e,
-                            img: image_map.lights[i as usize],
+                            img: image_map.lights[i as usize * last_light / threshold as usize],
                         },
                     )
                     .with_priority(Priority::Cleanup)
                 }));
                 event_listeners.extend_from_slice(&[
                     EventListener::new(
-                        EventTrigger::CounterPredicate(*counter, Rc::new(Box::new(|c| c == 3))),
+                        EventTrigger::CounterPredicate(
+                            *counter,
+                            Rc::new(Box::new(move |c| c >= threshold)),
+                        ),
                         rising.clone(),
                     )
                     .with_priority(Priority::Cleanup)
                     .with_modifier(EventTriggerModifier::Rising(false)),
                     EventListener::new(
-                        EventTrigger::CounterPredicate(*counter, Rc::new(Box::new(|c| c == 3))),
+                        EventTrigger::CounterPredicate(
+                            *counter,
+                            Rc::new(Box::new(move |c| c >= threshold)),
+                        ),
                         falling.clone(),
                     )
                     .with_priority(Priority::Cleanup)
@@ -971,29 +1304,154 @@ impl MapElement {
                     EventTrigger::PlayerIntersect,
                     Action::Reject("impassible"),
                 ));
+                ecs.opaque.insert(e, ());
             }
-            MapElement::MovingWall { direction, reset } => {
+            MapElement::MovingWall { path, crushing } => {
                 event_listeners.push(EventListener::new(
                     EventTrigger::PlayerIntersect,
-                    Action::Reject("impassible"),
+                    if *crushing {
+                        Action::KillPlayer("crushed by a wall")
+                    } else {
+                        Action::Reject("impassible")
+                    },
                 ));
-                if let Some((start, end)) = *reset {
-                    event_listeners.push(EventListener::new(
-                        EventTrigger::PositionPredicate(Rc::new(Box::new(move |pt| pt == end))),
-                        Action::SetPosition {
-                            target: e,
-                            position: start,
-                        },
-                    ));
-                }
-                ecs.movement.insert(
+                ecs.insert_patrol(e, path.clone());
+                ecs.opaque.insert(e, ());
+            }
+            MapElement::Spikes => {
+                event_listeners.push(EventListener::new(
+                    EventTrigger::PlayerIntersect,
+                    Action::KillPlayer("impaled on spikes"),
+                ));
+            }
+            MapElement::Pit => {
+                event_listeners.push(EventListener::new(
+                    EventTrigger::PlayerIntersect,
+                    Action::KillPlayer("fell into a pit"),
+                ));
+            }
+            MapElement::Guard {
+                path,
+                facing,
+                range,
+            } => {
+                ecs.insert_patrol(e, path.clone());
+                ecs.guards.insert(
                     e,
-                    Movement {
-                        direction: Some(*direction),
-                        movement_type: MovementType::Constant(*direction),
+                    Guard {
+                        facing: *facing,
+                        range: *range,
                     },
                 );
             }
+            MapElement::Platform(path) => {
+                ecs.insert_patrol(e, path.clone());
+                ecs.carriers.insert(e, ());
+            }
+            MapElement::Lever { on, off } => {
+                event_listeners.extend_from_slice(&[
+                    EventListener::new(
+                        EventTrigger::PlayerUse,
+                        Action::AlterCounter(e, Counter::STATE, Rc::new(Box::new(|c| 1 - c))),
+                    ),
+                    EventListener::new(
+                        EventTrigger::CounterPredicate(
+                            Counter::STATE,
+                            Rc::new(Box::new(|c| c == 1)),
+                        ),
+                        Action::All(vec![
+                            Action::SetImage {
+                                target: e,
+                                img: image_map.lever_on,
+                            },
+                            on.clone(),
+                        ]),
+                    )
+                    .with_priority(Priority::Cleanup)
+                    .with_modifier(EventTriggerModifier::Rising(false)),
+                    EventListener::new(
+                        EventTrigger::CounterPredicate(
+                            Counter::STATE,
+                            Rc::new(Box::new(|c| c == 1)),
+                        ),
+                        Action::All(vec![
+                            Action::SetImage {
+                                target: e,
+                                img: image_map.lever,
+                            },
+                            off.clone(),
+                        ]),
+                    )
+                    .with_priority(Priority::Cleanup)
+                    .with_modifier(EventTriggerModifier::Falling(false)),
+                ]);
+            }
+            MapElement::Switch(on) => {
+                event_listeners.extend_from_slice(&[
+                    EventListener::new(
+                        EventTrigger::PlayerUse,
+                        Action::AlterCounter(e, Counter::STATE, Rc::new(Box::new(|_| 1))),
+                    ),
+                    EventListener::new(
+                        EventTrigger::CounterPredicate(
+                            Counter::STATE,
+                            Rc::new(Box::new(|c| c == 1)),
+                        ),
+                        Action::All(vec![
+                            Action::SetImage {
+                                target: e,
+                                img: image_map.switch_on,
+                            },
+                            on.clone(),
+                        ]),
+                    )
+                    .with_priority(Priority::Cleanup)
+                    .with_modifier(EventTriggerModifier::Rising(false)),
+                ]);
+            }
+            MapElement::Gate {
+                kind,
+                inputs,
+                rising,
+                falling,
+            } => {
+                ecs.gates.insert(e, Gate::new(*kind, inputs.clone()));
+                event_listeners.extend_from_slice(&[
+                    EventListener::new(
+                        EventTrigger::CounterPredicate(
+                            Counter::STATE,
+                            Rc::new(Box::new(|c| c > 0)),
+                        ),
+                        rising.clone(),
+                    )
+                    .with_priority(Priority::Cleanup)
+                    .with_modifier(EventTriggerModifier::Rising(false)),
+                    EventListener::new(
+                        EventTrigger::CounterPredicate(
+                            Counter::STATE,
+                            Rc::new(Box::new(|c| c > 0)),
+                        ),
+                        falling.clone(),
+                    )
+                    .with_priority(Priority::Cleanup)
+                    .with_modifier(EventTriggerModifier::Falling(false)),
+                ]);
+            }
+            MapElement::Teleporter(destination) => {
+                ecs.teleporters.insert(e, *destination);
+            }
+            MapElement::Conveyor(direction) => {
+                ecs.conveyors.insert(e, *direction);
+            }
+            MapElement::Ice => {
+                ecs.ice.insert(e, ());
+            }
+            MapElement::OneWay(direction) => {
+                ecs.one_ways.insert(e, *direction);
+            }
+            MapElement::EdgeWall(side) => {
+                ecs.edge_walls.insert(e, *side);
+            }
             _ => {}
         };
         if !event_listeners.is_empty() {
@@ -1009,8 +1467,8 @@ impl MapElement {
 // "entity". This is pretty far from the standard ECS representation. To get there, we:
 // * Swap out the set with an array.
 // * Perform a array of structs to struct of arrays transform
-// * Re-use deleted slots using a generational index. Identify the entity with the generational
-// indices.
+// * Identify the entity with its index in the arrays. Deleted slots are never reused, so there's no
+// need for a generational index.
 // For our use case, many things are non-standard:
 // * Performance is much less of a concern. This game is in no way real-time: the game state can
 // only evolve at the speed of human input, and the number of entities is likely less than 100 at
@@ -1018,26 +1476,208 @@ impl MapElement {
 // * We'll have one ECS per frame, not one for the game as a while. Since almost everything happens
 // local to a frame, it's more important to make single-frame manipulation easy than cross-frame
 // manipulation. This means we'll be copying the ECS as a whole a lot: from a performance
-// perspective, making that cheap may be important. Persistent collections could help here,
-// although those fall back to more standard collections at small sizes, which we may be under.
+// perspective, making that cheap may be important, so components live in persistent vectors: a
+// copied ECS shares every component table with the original until one of them changes it.
 // * The good news is that if we're yolo copying the ECS every frame, the IDs can persist without
-// issue. In particular, this means an ECS ID can refer to the "same" object in multiple frames, so
-// we can use the ECS IDs for references.
-new_key_type! { pub struct Entity; }
-pub type Components<T> = SecondaryMap<Entity, T>;
-pub type SparseComponents<T> = SparseSecondaryMap<Entity, T>;
+// issue. In particular, this means an ECS ID can refer to the "same" object in a frame and every
+// frame after it, so we can use the ECS IDs for references.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Entity(u32);
+
+impl Entity {
+    // Entities are numbered in the order they were inserted, starting from 0.
+    pub fn index(self) -> u32 {
+        self.0
+    }
+}
+
+// A table of one component, backed by a persistent vector indexed by entity, so cloning it is cheap
+// and clones only copy the chunks they modify. It iterates in entity order.
+#[derive(Clone)]
+pub struct Components<T: Clone> {
+    slots: Vector<Option<T>>,
+    len: usize,
+}
+
+impl<T: Clone> Components<T> {
+    pub fn new() -> Self {
+        Components {
+            slots: Vector::new(),
+            len: 0,
+        }
+    }
+    pub fn contains_key(&self, entity: Entity) -> bool {
+        self.get(entity).is_some()
+    }
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        self.slots.get(entity.0 as usize)?.as_ref()
+    }
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        if !self.contains_key(entity) {
+            return None;
+        }
+        self.slots.get_mut(entity.0 as usize)?.as_mut()
+    }
+    // Returns the old value, if the entity already had one.
+    pub fn insert(&mut self, entity: Entity, value: T) -> Option<T> {
+        let ix = entity.0 as usize;
+        while self.slots.len() <= ix {
+            self.slots.push_back(None);
+        }
+        let old = self.slots.set(ix, Some(value));
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        if !self.contains_key(entity) {
+            return None;
+        }
+        self.len -= 1;
+        self.slots.set(entity.0 as usize, None)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(ix, slot)| slot.as_ref().map(|value| (Entity(ix as u32), value)))
+    }
+    // Copies every chunk of the table that isn't already unique to this ECS.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> + '_ {
+        self.slots
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(ix, slot)| slot.as_mut().map(|value| (Entity(ix as u32), value)))
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    // Whether the two tables still share all of their storage, as they do right after a clone.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.slots.ptr_eq(&other.slots)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T: Clone> Default for Components<T> {
+    fn default() -> Self {
+        Components::new()
+    }
+}
+
+impl<T: Clone + fmt::Debug> fmt::Debug for Components<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Clone> Index<Entity> for Components<T> {
+    type Output = T;
+    fn index(&self, entity: Entity) -> &T {
+        self.get(entity).expect("invalid Components key used")
+    }
+}
+
+impl<T: Clone> IndexMut<Entity> for Components<T> {
+    fn index_mut(&mut self, entity: Entity) -> &mut T {
+        self.get_mut(entity).expect("invalid Components key used")
+    }
+}
+
+// The live entities, kept persistent like the component tables. A removed entity's index is never
+// reused, so within a branch of the history an entity means the same thing in every frame that has
+// it. Each frame counts its own entities though, so sibling branches give the same index to
+// whatever they insert after they split, such as players from different portals.
+#[derive(Clone, Debug, Default)]
+pub struct Entities {
+    live: Components<()>,
+    next_index: u32,
+}
+
+impl Entities {
+    pub fn new() -> Self {
+        Entities {
+            live: Components::new(),
+            next_index: 0,
+        }
+    }
+    pub fn insert(&mut self) -> Entity {
+        let entity = Entity(self.next_index);
+        self.next_index += 1;
+        self.live.insert(entity, ());
+        entity
+    }
+    // How many entities have ever been inserted. Two frames agree on every entity below the count
+    // of the frame they branched from.
+    pub fn inserted(&self) -> u32 {
+        self.next_index
+    }
+    pub fn contains_key(&self, entity: Entity) -> bool {
+        self.live.contains_key(entity)
+    }
+    pub fn remove(&mut self, entity: Entity) -> Option<()> {
+        self.live.remove(entity)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &())> + '_ {
+        self.live.iter()
+    }
+}
+
+// A copy-on-write pointer, for things like the portal graphs that don't have a persistent
+// equivalent. Clones share the value until one of them is mutably dereferenced, which copies it.
+#[derive(Clone, Debug, Default)]
+pub struct Shared<T: Clone>(Rc<T>);
+
+impl<T: Clone> Shared<T> {
+    pub fn new(value: T) -> Self {
+        Shared(Rc::new(value))
+    }
+}
+
+impl<T: Clone> Deref for Shared<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Clone> DerefMut for Shared<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        Rc::make_mut(&mut self.0)
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct ECS {
     // TODO: leak ImageMap at launch, then stick a reference to it in every ECS so an ECS can
     // insert things itself.
-    pub entities: HopSlotMap<Entity, ()>,
+    pub entities: Entities,
     pub images: Components<DrawRef>,
     pub positions: Components<Point>,
     pub event_listeners: Components<Vec<EventListener>>,
     pub disabled_event_groups: Components<EnumSet<Group>>,
-    pub counters: Components<EnumMap<Counter, i64>>,
+    pub counters: Components<HashMap<Counter, i64>>,
+    pub declared_counters: HashSet<Counter>,
     pub players: Components<Inventory>,
     pub movement: Components<Movement>,
+    pub patrols: Components<Patrol>,
+    pub guards: Components<Guard>,
+    // Blocks guards' line of sight.
+    pub opaque: Components<()>,
+    // Moves players standing on it along with it.
+    pub carriers: Components<()>,
+    pub gates: Components<Gate>,
+    // Where each teleporter sends things.
+    pub teleporters: Components<Point>,
+    // Which way each conveyor pushes.
+    pub conveyors: Components<Direction>,
+    pub ice: Components<()>,
+    pub one_ways: Components<Direction>,
+    // Which side of its tile each edge wall sits on.
+    pub edge_walls: Components<Direction>,
 }
 
 impl ECS {
@@ -1047,7 +1687,7 @@ impl ECS {
         pos: Point,
         inventory: Inventory,
     ) -> Entity {
-        let player = self.entities.insert(());
+        let player = self.entities.insert();
         self.players.insert(player, inventory);
         self.positions.insert(player, pos);
         self.images.insert(player, image_map.player);
@@ -1067,6 +1707,48 @@ impl ECS {
         );
         player
     }
+    pub fn declare_counter(&mut self, name: &'static str) -> Counter {
+        let counter = Counter(name);
+        self.declared_counters.insert(counter);
+        counter
+    }
+    fn check_declared(&self, counter: Counter) {
+        if counter != Counter::STATE && !self.declared_counters.contains(&counter) {
+            panic!("Counter {:?} was never declared", counter);
+        }
+    }
+    pub fn counter(&self, entity: Entity, counter: Counter) -> i64 {
+        self.check_declared(counter);
+        self.counters
+            .get(entity)
+            .and_then(|counters| counters.get(&counter))
+            .copied()
+            .unwrap_or(0)
+    }
+    pub fn counter_mut(&mut self, entity: Entity, counter: Counter) -> &mut i64 {
+        self.check_declared(counter);
+        if !self.counters.contains_key(entity) {
+            self.counters.insert(entity, HashMap::new());
+        }
+        self.counters
+            .get_mut(entity)
+            .expect("Should have ensured that the counters existed")
+            .entry(counter)
+            .or_insert(0)
+    }
+    pub fn insert_patrol(&mut self, entity: Entity, patrol: Patrol) {
+        if let Some(counter) = patrol.trigger {
+            self.check_declared(counter);
+        }
+        self.movement.insert(
+            entity,
+            Movement {
+                direction: None,
+                movement_type: MovementType::Patrol,
+            },
+        );
+        self.patrols.insert(entity, patrol);
+    }
     pub fn verify(&self) {
         for (player, _inventory) in self.players.iter() {
             if !self.entities.contains_key(player) {
@@ -1082,7 +1764,7 @@ impl ECS {
 // TODO: doing a mutable join is really tricky here: get_mut doesn't trust that all your entities
 // are different. If you've only got one mutable component, that's fine: you use that as your base.
 // If you've got more, you probably need to do it by hand.
-pub fn inner_join<I: Iterator<Item = (Entity, T1)>, T1, T2>(
+pub fn inner_join<I: Iterator<Item = (Entity, T1)>, T1, T2: Clone>(
     iter: I,
     other: &Components<T2>,
 ) -> impl Iterator<Item = (Entity, (T1, &T2))> {
@@ -1108,6 +1790,8 @@ pub enum EventTrigger {
     PlayerIntersect,
     PlayerNotIntersect,
     PlayerIntersectHasItems(Item, usize),
+    // A player on this tile made a `Move::Use` this turn.
+    PlayerUse,
     ItemIntersect(Item),
     CounterPredicate(
         Counter,
@@ -1116,9 +1800,17 @@ pub enum EventTrigger {
     PositionPredicate(#[derivative(Debug = "ignore")] Rc<Box<dyn Fn(Point) -> bool>>),
 }
 
-#[derive(Copy, Clone, Debug, Enum)]
-pub enum Counter {
-    Unlock,
+// Counters are named by the level designer. Levels declare the counters they use up front with
+// `ECS::declare_counter`, so a misspelled name gets caught rather than silently reading 0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Counter(&'static str);
+
+impl Counter {
+    // Built in, so always declared. Levers, switches and gates keep their own state in it.
+    pub const STATE: Counter = Counter("state");
+    pub fn name(self) -> &'static str {
+        self.0
+    }
 }
 
 #[derive(Clone, Derivative)]
@@ -1131,6 +1823,8 @@ pub enum Action {
     ),
     // Implicitly uses intersecting player; should maybe take an argument for how to find the player.
     PlayerMarkUsed(Item, usize),
+    // Also implicitly uses the intersecting player. The message explains the death.
+    KillPlayer(&'static str),
     Reject(&'static str),
     SetImage {
         target: Entity,
@@ -1143,6 +1837,9 @@ pub enum Action {
         position: Point,
     },
     All(Vec<Action>),
+    // Runs the action this many turns from now. The countdown is part of the `GameFrame`, so each
+    // branch of history keeps its own.
+    Delay(usize, Box<Action>),
 }
 
 #[derive(Clone, Debug)]
@@ -1178,6 +1875,9 @@ pub struct EventListener {
 pub enum MovementType {
     PlayerControlled,
     Constant(Direction),
+    // Follows the entity's `Patrol` component. The direction is picked at the start of each turn,
+    // so that counters set during the previous turn take effect immediately.
+    Patrol,
 }
 
 #[derive(Clone, Debug)]
@@ -1186,6 +1886,204 @@ pub struct Movement {
     pub movement_type: MovementType,
 }
 
+// What a patrol does once it reaches its last waypoint.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum PatrolMode {
+    // Walk back to the first waypoint.
+    Loop,
+    // Retrace the waypoints in reverse.
+    PingPong,
+    // Teleport to the first waypoint, which takes a turn.
+    Reset,
+}
+
+#[derive(Clone, Debug)]
+pub struct Patrol {
+    pub waypoints: Vec<Point>,
+    pub mode: PatrolMode,
+    pub next: usize,
+    pub reversed: bool,
+    // Turns to wait at each waypoint before moving on.
+    pub pause: usize,
+    pub waited: usize,
+    // If set, only moves while this counter is positive (or, if inverted, while it isn't).
+    pub trigger: Option<Counter>,
+    pub inverted: bool,
+}
+
+impl Patrol {
+    pub fn new(waypoints: Vec<Point>, mode: PatrolMode) -> Self {
+        Patrol {
+            waypoints,
+            mode,
+            next: 0,
+            reversed: false,
+            pause: 0,
+            waited: 0,
+            trigger: None,
+            inverted: false,
+        }
+    }
+    // For entities that start partway along their path.
+    pub fn heading_to(mut self, next: usize) -> Self {
+        self.next = next;
+        self
+    }
+    pub fn with_pause(mut self, pause: usize) -> Self {
+        self.pause = pause;
+        self
+    }
+    pub fn with_trigger(mut self, counter: Counter) -> Self {
+        self.trigger = Some(counter);
+        self
+    }
+    pub fn with_inverted_trigger(mut self, counter: Counter) -> Self {
+        self.trigger = Some(counter);
+        self.inverted = true;
+        self
+    }
+    pub fn active(&self, counters: Option<&HashMap<Counter, i64>>) -> bool {
+        match self.trigger {
+            None => true,
+            Some(counter) => {
+                let count = counters
+                    .and_then(|counters| counters.get(&counter))
+                    .copied()
+                    .unwrap_or(0);
+                (count > 0) != self.inverted
+            }
+        }
+    }
+    // Advances past the waypoint we're standing on, if we're done pausing there, and returns the
+    // direction to head in to reach the next one. Moves horizontally before vertically. In `Reset`
+    // mode, this may teleport `position` instead, in which case there's no direction.
+    pub fn step(&mut self, position: &mut Point) -> Option<Direction> {
+        if self.waypoints.is_empty() {
+            return None;
+        }
+        if self.waypoints[self.next] == *position {
+            if self.waited < self.pause {
+                self.waited += 1;
+                return None;
+            }
+            self.waited = 0;
+            let start = *position;
+            self.advance(position);
+            if *position != start {
+                return None;
+            }
+        }
+        let target = self.waypoints[self.next];
+        match (target.x.cmp(&position.x), target.y.cmp(&position.y)) {
+            (Ordering::Less, _) => Some(Direction::Left),
+            (Ordering::Greater, _) => Some(Direction::Right),
+            (Ordering::Equal, Ordering::Less) => Some(Direction::Up),
+            (Ordering::Equal, Ordering::Greater) => Some(Direction::Down),
+            (Ordering::Equal, Ordering::Equal) => None,
+        }
+    }
+    fn advance(&mut self, position: &mut Point) {
+        let last = self.waypoints.len() - 1;
+        match self.mode {
+            PatrolMode::Loop => self.next = (self.next + 1) % self.waypoints.len(),
+            PatrolMode::PingPong => {
+                if last == 0 {
+                    return;
+                }
+                if self.next == last {
+                    self.reversed = true;
+                } else if self.next == 0 {
+                    self.reversed = false;
+                }
+                if self.reversed {
+                    self.next -= 1;
+                } else {
+                    self.next += 1;
+                }
+            }
+            PatrolMode::Reset => {
+                if self.next == last {
+                    *position = self.waypoints[0];
+                    self.next = min(1, last);
+                } else {
+                    self.next += 1;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum GateKind {
+    And,
+    Or,
+    // With several inputs, this is a NOR.
+    Not,
+    Xor,
+    // Turned on by the first input and off by the second. Stays put when neither is on.
+    Latch,
+    // Repeats its inputs (ORed together) this many turns later.
+    Delay(usize),
+}
+
+// An input is on when the input entity's counter is positive. The output is written to the gate's
+// own `Counter::STATE`, as either 0 or 1, so gates can feed into each other.
+#[derive(Clone, Debug)]
+pub struct Gate {
+    pub kind: GateKind,
+    pub inputs: Vec<(Entity, Counter)>,
+    // For a latch, the last output. For a delay, the inputs from prior turns, oldest first.
+    state: VecDeque<bool>,
+}
+
+impl Gate {
+    pub fn new(kind: GateKind, inputs: Vec<(Entity, Counter)>) -> Self {
+        let state = match kind {
+            GateKind::Latch => iter::once(false).collect(),
+            GateKind::Delay(turns) => iter::repeat(false).take(turns).collect(),
+            _ => VecDeque::new(),
+        };
+        Gate {
+            kind,
+            inputs,
+            state,
+        }
+    }
+    pub fn output(&self, inputs: &[bool]) -> bool {
+        let any = inputs.iter().any(|&x| x);
+        match self.kind {
+            GateKind::And => inputs.iter().all(|&x| x),
+            GateKind::Or => any,
+            GateKind::Not => !any,
+            GateKind::Xor => inputs.iter().filter(|&&x| x).count() % 2 == 1,
+            GateKind::Latch => match (inputs.first().copied(), inputs.get(1).copied()) {
+                (Some(true), _) => true,
+                (_, Some(true)) => false,
+                _ => self.state[0],
+            },
+            GateKind::Delay(_) => self.state.front().copied().unwrap_or(any),
+        }
+    }
+    // Should be called once per turn, after all the inputs have settled.
+    pub fn commit(&mut self, inputs: &[bool]) {
+        match self.kind {
+            GateKind::Latch => self.state[0] = self.output(inputs),
+            GateKind::Delay(_) => {
+                if self.state.pop_front().is_some() {
+                    self.state.push_back(inputs.iter().any(|&x| x));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Guard {
+    pub facing: Direction,
+    pub range: i32,
+}
+
 impl EventListener {
     pub fn new(trigger: EventTrigger, action: Action) -> Self {
         EventListener {
@@ -1228,15 +2126,160 @@ pub fn player_at(ecs: &ECS, pt: Point) -> Option<Entity> {
     entities.into_iter().find(|e| ecs.players.contains_key(*e))
 }
 
+// A player at `pt` other than `player`, to hand things to.
+pub fn other_player_at(ecs: &ECS, pt: Point, player: Entity) -> Option<Entity> {
+    let entities = entities_at(ecs, pt);
+    entities
+        .into_iter()
+        .find(|&e| e != player && ecs.players.contains_key(e))
+}
+
+pub fn opaque_at(ecs: &ECS, pt: Point) -> bool {
+    entities_at(ecs, pt)
+        .into_iter()
+        .any(|e| ecs.opaque.contains_key(e))
+}
+
+// Whether something at `pt` would reject a player walking into it.
+pub fn blocked_at(ecs: &ECS, pt: Point) -> bool {
+    entities_at(ecs, pt).into_iter().any(|e| {
+        let disabled = ecs.disabled_event_groups.get(e);
+        ecs.event_listeners.get(e).map_or(false, |event_listeners| {
+            event_listeners.iter().any(|event_listener| {
+                match (&event_listener.trigger, &event_listener.action) {
+                    (EventTrigger::PlayerIntersect, Action::Reject(_)) => {
+                        !disabled.map_or(false, |disabled| disabled.contains(event_listener.group))
+                    }
+                    _ => false,
+                }
+            })
+        })
+    })
+}
+
+// Whether an edge wall or one-way tile stops a move from `from` in `direction`. Anything that fills
+// the whole destination tile is handled by its event listeners instead.
+pub fn move_blocked(ecs: &ECS, from: Point, direction: Direction) -> bool {
+    let to = from + direction.delta();
+    let wrong_way = |pt: Point| {
+        entities_at(ecs, pt)
+            .into_iter()
+            .filter_map(|e| ecs.one_ways.get(e))
+            .any(|&one_way| one_way != direction)
+    };
+    edge_wall_between(ecs, from, direction) || wrong_way(from) || wrong_way(to)
+}
+
+pub fn edge_wall_between(ecs: &ECS, from: Point, direction: Direction) -> bool {
+    let to = from + direction.delta();
+    let wall_on_side = |pt: Point, side: Direction| {
+        entities_at(ecs, pt)
+            .into_iter()
+            .any(|e| ecs.edge_walls.get(e) == Some(&side))
+    };
+    wall_on_side(from, direction) || wall_on_side(to, direction.opposite())
+}
+
+// Where an item thrown from `from` lands. It flies until the next tile would stop a player, or
+// until it's gone `THROW_RANGE` tiles. One-way tiles and anything lethal are flown straight over.
+pub fn throw_landing(ecs: &ECS, from: Point, direction: Direction) -> Point {
+    let mut pt = from;
+    for _ in 0..THROW_RANGE {
+        let next = pt + direction.delta();
+        if edge_wall_between(ecs, pt, direction) || blocked_at(ecs, next) {
+            break;
+        }
+        pt = next;
+    }
+    pt
+}
+
+pub fn on_ice(ecs: &ECS, pt: Point) -> bool {
+    entities_at(ecs, pt)
+        .into_iter()
+        .any(|e| ecs.ice.contains_key(e))
+}
+
+pub fn conveyor_at(ecs: &ECS, pt: Point) -> Option<Direction> {
+    entities_at(ecs, pt)
+        .into_iter()
+        .find_map(|e| ecs.conveyors.get(e))
+        .copied()
+}
+
+// The tiles a guard can see: a 90 degree cone in the direction it's facing, out to its range,
+// minus anything hidden behind an opaque entity.
+pub fn visible_tiles(ecs: &ECS, guard: Entity) -> Vec<Point> {
+    let (position, guard) = match (ecs.positions.get(guard), ecs.guards.get(guard)) {
+        (Some(&position), Some(guard)) => (position, guard),
+        _ => return Vec::new(),
+    };
+    let forward = guard.facing.delta();
+    let sideways = nalgebra::Vector2::new(forward.y, forward.x);
+    let mut tiles = Vec::new();
+    for distance in 0..=guard.range {
+        for offset in -distance..=distance {
+            let pt = position + forward * distance + sideways * offset;
+            if line_of_sight(ecs, position, pt) {
+                tiles.push(pt);
+            }
+        }
+    }
+    tiles
+}
+
+// Follows the chain of teleporters starting at `start`. Stops short of any destination that's
+// `occupied` or opaque, and of any teleporter already visited, so paired teleporters don't bounce
+// things back and forth forever.
+pub fn teleport_destination<F: Fn(Point) -> bool>(
+    ecs: &ECS,
+    start: Point,
+    occupied: F,
+) -> Option<Point> {
+    let mut visited = vec![start];
+    let mut pt = start;
+    while let Some(&destination) = entities_at(ecs, pt)
+        .into_iter()
+        .find_map(|e| ecs.teleporters.get(e))
+    {
+        if visited.contains(&destination) || occupied(destination) || opaque_at(ecs, destination) {
+            break;
+        }
+        visited.push(destination);
+        pt = destination;
+    }
+    if pt == start {
+        None
+    } else {
+        Some(pt)
+    }
+}
+
+fn line_of_sight(ecs: &ECS, from: Point, to: Point) -> bool {
+    let delta = to - from;
+    let steps = std::cmp::max(delta.x.abs(), delta.y.abs());
+    (1..steps).all(|i| {
+        let t = i as f32 / steps as f32;
+        let pt = Point::new(
+            from.x + (delta.x as f32 * t).round() as i32,
+            from.y + (delta.y as f32 * t).round() as i32,
+        );
+        !opaque_at(ecs, pt)
+    }) && (steps == 0 || !opaque_at(ecs, to))
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
-        add_to_cells, ActualInventory, HypotheticalInventory, Inventory, InventoryCell, Item, Key,
+        add_to_cells, ActualInventory, Components, Entities, Entity, HypotheticalInventory,
+        Inventory, InventoryCell, Item, Key,
     };
     use proptest::{
         arbitrary::{any, Arbitrary},
+        collection,
         strategy::{BoxedStrategy, Strategy},
     };
+    use std::collections::BTreeMap;
 
     impl Arbitrary for Item {
         type Parameters = ();
@@ -1270,6 +2313,31 @@ mod tests {
                 .boxed()
         }
     }
+    proptest! {
+        #[test]
+        fn test_components_match_map(ops in collection::vec((0usize..20, any::<Option<i32>>()), 0..100)) {
+            let mut entities = Entities::new();
+            let keys: Vec<Entity> = (0..20).map(|_| entities.insert()).collect();
+            let mut components = Components::new();
+            let mut expected = BTreeMap::new();
+            // Earlier clones must be unaffected by later changes.
+            let mut snapshots = Vec::new();
+            for (ix, value) in ops {
+                snapshots.push((components.clone(), expected.clone()));
+                match value {
+                    Some(value) => assert_eq!(components.insert(keys[ix], value), expected.insert(keys[ix], value)),
+                    None => assert_eq!(components.remove(keys[ix]), expected.remove(keys[ix])),
+                }
+            }
+            snapshots.push((components, expected));
+            for (components, expected) in snapshots {
+                let actual: Vec<(Entity, i32)> = components.iter().map(|(e, &v)| (e, v)).collect();
+                assert_eq!(components.len(), expected.len());
+                let expected: Vec<(Entity, i32)> = expected.iter().map(|(&e, &v)| (e, v)).collect();
+                assert_eq!(actual, expected);
+            }
+        }
+    }
     proptest! {
         #[test]
         fn test_merge_in_empty_hypothetical(actual in any::<ActualInventory>()) {